@@ -0,0 +1,140 @@
+use crate::tracker::{announce, AnnounceParams, PeerAddr, TrackerResponse};
+use crate::udp_tracker::{UdpTracker, UDP_SCHEME};
+use anyhow::{Context, Result};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How long to wait between announces when the tracker doesn't set a min interval
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+// How long to wait before trying again after a failed announce
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+// Longer intervals are cut to this, which also keeps huge ones from overflowing an Instant
+const MAX_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// A UDP tracker gets 2 + 4 seconds per request rather than the standard schedule of over two
+// hours, so stopping doesn't wait that long for an announce in flight. Lost announces are retried
+// after RETRY_DELAY anyway.
+const UDP_BASE_TIMEOUT: Duration = Duration::from_secs(2);
+const UDP_MAX_RETRANSMISSIONS: u32 = 1;
+
+enum Command {
+    AnnounceSoon,
+    Stop,
+}
+
+/// Re-announces to a tracker in the background on the interval it asked for, handing every new
+/// peer list to a callback.
+pub struct Announcer {
+    params: Arc<Mutex<AnnounceParams>>,
+    commands: Sender<Command>,
+    handle: JoinHandle<()>,
+}
+
+impl Announcer {
+    /// Start re-announcing after the first `response` of the tracker was received.
+    pub fn start(
+        announce_url: String,
+        params: AnnounceParams,
+        response: &TrackerResponse,
+        on_peers: impl Fn(Vec<PeerAddr>) + Send + 'static,
+    ) -> Self {
+        let params = Arc::new(Mutex::new(params));
+        let (commands, command_rx) = mpsc::channel();
+        let mut schedule = Schedule::new(response);
+
+        let thread_params = params.clone();
+        let handle = thread::spawn(move || loop {
+            let timeout = schedule.next.saturating_duration_since(Instant::now());
+            match command_rx.recv_timeout(timeout) {
+                Ok(Command::AnnounceSoon) => schedule.hurry(),
+                Ok(Command::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                Err(RecvTimeoutError::Timeout) => {
+                    let params = thread_params.lock().unwrap().clone();
+                    match reannounce(&announce_url, &params) {
+                        Ok(response) => {
+                            schedule = Schedule::new(&response);
+                            on_peers(response.peer_addr_list);
+                        }
+                        Err(e) => {
+                            println!("failed to re-announce: {:#}", e);
+                            schedule.retry();
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            params,
+            commands,
+            handle,
+        }
+    }
+
+    /// Ask for more peers as soon as the tracker's min interval allows it.
+    pub fn announce_soon(&self) {
+        // Sending only fails once the thread is gone, and then there is nothing left to hurry
+        let _ = self.commands.send(Command::AnnounceSoon);
+    }
+
+    pub fn record_downloaded(&self, bytes: u64) {
+        self.params.lock().unwrap().record_downloaded(bytes);
+    }
+
+    pub fn record_uploaded(&self, bytes: u64) {
+        self.params.lock().unwrap().record_uploaded(bytes);
+    }
+
+    /// Stop re-announcing, and hand back the parameters with the stats gathered so far.
+    pub fn stop(self) -> AnnounceParams {
+        let _ = self.commands.send(Command::Stop);
+        // An announce in flight is waited for, so it can't race with the caller's next one
+        let _ = self.handle.join();
+        Arc::try_unwrap(self.params)
+            .map(|params| params.into_inner().unwrap())
+            .unwrap_or_else(|params| params.lock().unwrap().clone())
+    }
+}
+
+fn reannounce(announce_url: &str, params: &AnnounceParams) -> Result<TrackerResponse> {
+    if announce_url.starts_with(UDP_SCHEME) {
+        return UdpTracker::new(announce_url)
+            .context("set up udp tracker")?
+            .with_timeouts(UDP_BASE_TIMEOUT, UDP_MAX_RETRANSMISSIONS)
+            .announce(params);
+    }
+    announce(announce_url, params)
+}
+
+struct Schedule {
+    // When the next regular announce is due
+    next: Instant,
+    // The earliest the tracker wants to hear from us again
+    earliest: Instant,
+}
+
+impl Schedule {
+    fn new(response: &TrackerResponse) -> Self {
+        let last = Instant::now();
+        let interval = Duration::from_secs(response.interval.max(1) as u64).min(MAX_INTERVAL);
+        let min_interval = response
+            .min_interval
+            .map(|min_interval| Duration::from_secs(min_interval.max(0) as u64))
+            .unwrap_or(DEFAULT_MIN_INTERVAL)
+            .min(interval);
+        Self {
+            next: last + interval,
+            earliest: last + min_interval,
+        }
+    }
+
+    fn hurry(&mut self) {
+        self.next = self.next.min(self.earliest.max(Instant::now()));
+    }
+
+    fn retry(&mut self) {
+        self.next = Instant::now() + RETRY_DELAY;
+        self.earliest = self.next;
+    }
+}
@@ -0,0 +1,191 @@
+use crate::http_client::async_client;
+use crate::tracker::{
+    build_request_url, parse_response, remember_tracker_id, AnnounceParams, PeerAddr,
+    TrackerResponse,
+};
+use crate::udp_tracker::{UdpTracker, UDP_SCHEME};
+use anyhow::{bail, Context, Result};
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+/// What announcing to several trackers at once came up with.
+#[derive(Debug)]
+pub struct SwarmResponse {
+    /// The peers of every tracker that answered, without duplicates
+    pub peer_addr_list: Vec<PeerAddr>,
+    /// The answer of each tracker that answered in time
+    pub responses: Vec<(String, TrackerResponse)>,
+    /// Why the other trackers didn't answer
+    pub failures: Vec<(String, anyhow::Error)>,
+}
+
+impl SwarmResponse {
+    /// The warnings of the trackers that sent one, with their announce URL.
+    pub fn warnings(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.responses
+            .iter()
+            .filter_map(|(announce_url, response)| {
+                response
+                    .warning_message
+                    .as_deref()
+                    .map(|warning| (announce_url.as_str(), warning))
+            })
+    }
+}
+
+/// Announce to a single tracker without blocking the runtime's threads.
+pub async fn announce(announce_url: &str, params: &AnnounceParams) -> Result<TrackerResponse> {
+    announce_with_udp_timeout(announce_url, params, None).await
+}
+
+// The UDP client waits on its socket, so it gets a thread of its own. That thread can't be
+// cancelled, and dropping the future only stops waiting for it, so with `udp_timeout` it gives
+// up by itself instead of going through the whole retransmission schedule.
+async fn announce_with_udp_timeout(
+    announce_url: &str,
+    params: &AnnounceParams,
+    udp_timeout: Option<Duration>,
+) -> Result<TrackerResponse> {
+    if announce_url.starts_with(UDP_SCHEME) {
+        let announce_url = announce_url.to_string();
+        let params = params.clone();
+        return tokio::task::spawn_blocking(move || {
+            let mut tracker = UdpTracker::new(&announce_url).context("set up udp tracker")?;
+            if let Some(timeout) = udp_timeout {
+                tracker = tracker.with_timeouts(timeout, 0);
+            }
+            tracker.announce(&params)
+        })
+        .await
+        .context("join udp announce")?;
+    }
+
+    let url = build_request_url(announce_url, params);
+    let response_in_bytes = async_client()?
+        .get(url)
+        .send()
+        .await
+        .context("request the url")?
+        .bytes()
+        .await
+        .context("read request as bytes")?;
+    let mut response = parse_response(&response_in_bytes)?;
+    remember_tracker_id(announce_url, &response);
+    resolve_peer_hosts(&mut response).await;
+    Ok(response)
+}
+
+// Look up the peers named by hostname without blocking the runtime's threads. Peers whose name
+// doesn't resolve are skipped.
+async fn resolve_peer_hosts(response: &mut TrackerResponse) {
+    for peer_host in std::mem::take(&mut response.peer_hosts) {
+        let Ok(mut addrs) =
+            tokio::net::lookup_host((peer_host.host.as_str(), peer_host.port)).await
+        else {
+            continue;
+        };
+        if let Some(addr) = addrs.next() {
+            response.peer_addr_list.push(PeerAddr {
+                addr,
+                peer_id: peer_host.peer_id,
+            });
+        }
+    }
+}
+
+/// Announce to every tracker concurrently, giving each of them `timeout` to answer, and merge
+/// the peers they hand out. Only fails when no tracker answered. Dropping the returned future
+/// cancels the announces still in flight, except UDP ones which give up on their own within
+/// twice `timeout`.
+pub async fn track(
+    announce_urls: &[String],
+    params: &AnnounceParams,
+    timeout: Duration,
+) -> Result<SwarmResponse> {
+    let mut announces = JoinSet::new();
+    for (i, announce_url) in announce_urls.iter().enumerate() {
+        let announce_url = announce_url.clone();
+        let params = params.clone();
+        announces.spawn(async move {
+            let announce = announce_with_udp_timeout(&announce_url, &params, Some(timeout));
+            let response = tokio::time::timeout(timeout, announce)
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {:?}", timeout)));
+            (i, announce_url, response)
+        });
+    }
+
+    // Keep the trackers' order so the merged peer list doesn't depend on who answered first
+    let mut results = Vec::with_capacity(announce_urls.len());
+    while let Some(joined) = announces.join_next().await {
+        results.push(joined.context("join announce")?);
+    }
+    results.sort_by_key(|(i, _, _)| *i);
+
+    let mut swarm_response = SwarmResponse {
+        peer_addr_list: vec![],
+        responses: vec![],
+        failures: vec![],
+    };
+    for (_, announce_url, response) in results {
+        match response {
+            Ok(response) => {
+                merge_peers(&mut swarm_response.peer_addr_list, &response.peer_addr_list);
+                swarm_response.responses.push((announce_url, response));
+            }
+            Err(e) => swarm_response.failures.push((announce_url, e)),
+        }
+    }
+    if swarm_response.responses.is_empty() {
+        match swarm_response.failures.pop() {
+            Some((announce_url, e)) => {
+                return Err(e.context(format!("no tracker answered, last was {}", announce_url)))
+            }
+            None => bail!("no trackers to announce to"),
+        }
+    }
+    Ok(swarm_response)
+}
+
+/// Same as [`announce`] for callers outside of a runtime.
+pub fn announce_blocking(announce_url: &str, params: &AnnounceParams) -> Result<TrackerResponse> {
+    block_on(announce(announce_url, params))?
+}
+
+/// Same as [`track`] for callers outside of a runtime, like the CLI.
+pub fn track_blocking(
+    announce_urls: &[String],
+    params: &AnnounceParams,
+    timeout: Duration,
+) -> Result<SwarmResponse> {
+    block_on(track(announce_urls, params, timeout))?
+}
+
+fn block_on<F: Future>(future: F) -> Result<F::Output> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("build tokio runtime")?;
+    let output = runtime.block_on(future);
+    // UDP announces past their timeout end on their own, nothing waits for them
+    runtime.shutdown_background();
+    Ok(output)
+}
+
+// Add the peers we don't know yet, and the peer ids we didn't know yet
+fn merge_peers(peer_addr_list: &mut Vec<PeerAddr>, new_peers: &[PeerAddr]) {
+    for new_peer in new_peers {
+        match peer_addr_list
+            .iter_mut()
+            .find(|peer| peer.addr == new_peer.addr)
+        {
+            Some(peer) => {
+                if peer.peer_id.is_none() {
+                    peer.peer_id = new_peer.peer_id;
+                }
+            }
+            None => peer_addr_list.push(*new_peer),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+/// Which pieces of a torrent a peer has, one bit per piece with the first piece in the high bit
+/// of the first byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    piece_count: usize,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum BitfieldError {
+    #[error("bitfield should be {expected} bytes, got {got}")]
+    WrongLength { expected: usize, got: usize },
+    #[error("bitfield has bits set past the last piece")]
+    SpareBitsSet,
+    #[error("#{piece_index} piece is out of range, the torrent has {piece_count} pieces")]
+    PieceOutOfRange {
+        piece_index: usize,
+        piece_count: usize,
+    },
+}
+
+impl Bitfield {
+    /// No pieces at all, which peers that have nothing may say by not sending a bitfield.
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            bytes: vec![0; piece_count.div_ceil(8)],
+            piece_count,
+        }
+    }
+
+    /// Every piece, as a seeder has.
+    pub fn full(piece_count: usize) -> Self {
+        let mut bitfield = Self::new(piece_count);
+        for piece_index in 0..piece_count {
+            bitfield.bytes[piece_index / 8] |= 0x80 >> (piece_index % 8);
+        }
+        bitfield
+    }
+
+    /// Take the payload of a bitfield message, which has to be exactly as long as the torrent
+    /// needs and have the spare bits at the end cleared.
+    pub fn from_payload(payload: &[u8], piece_count: usize) -> Result<Self, BitfieldError> {
+        let expected = piece_count.div_ceil(8);
+        if payload.len() != expected {
+            return Err(BitfieldError::WrongLength {
+                expected,
+                got: payload.len(),
+            });
+        }
+        let spare_bits = expected * 8 - piece_count;
+        if spare_bits > 0 && payload[expected - 1] & ((1 << spare_bits) - 1) != 0 {
+            return Err(BitfieldError::SpareBitsSet);
+        }
+        Ok(Self {
+            bytes: payload.to_vec(),
+            piece_count,
+        })
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.piece_count
+    }
+
+    /// Pieces out of range are never had.
+    pub fn has(&self, piece_index: usize) -> bool {
+        piece_index < self.piece_count
+            && self.bytes[piece_index / 8] & (0x80 >> (piece_index % 8)) != 0
+    }
+
+    /// Mark a piece as had, e.g. on a have message.
+    pub fn set(&mut self, piece_index: usize) -> Result<(), BitfieldError> {
+        if piece_index >= self.piece_count {
+            return Err(BitfieldError::PieceOutOfRange {
+                piece_index,
+                piece_count: self.piece_count,
+            });
+        }
+        self.bytes[piece_index / 8] |= 0x80 >> (piece_index % 8);
+        Ok(())
+    }
+
+    /// How many pieces are had.
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.piece_count
+    }
+
+    /// The payload of a bitfield message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
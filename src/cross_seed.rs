@@ -0,0 +1,139 @@
+use crate::torrent_file::{TorrentFileEntry, TorrentFileInfo};
+use anyhow::{bail, Context, Ok, Result};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+/// A local file holding the data of one of the torrent's files.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DataMatch {
+    /// Path of the file inside the torrent
+    pub torrent_path: PathBuf,
+    /// Where the data was found on disk
+    pub local_path: PathBuf,
+    /// How many pieces lying entirely within the file were hashed to confirm the match. Zero
+    /// means the file is too small to contain a whole piece and only its size matched.
+    pub verified_pieces: usize,
+}
+
+/// Scan `search_dir` recursively for files holding the data of the torrent's files. Candidates
+/// are picked by size and confirmed by hashing every piece that falls entirely within them.
+pub fn find_data(info: &TorrentFileInfo, search_dir: &Path) -> Result<Vec<DataMatch>> {
+    if info.piece_length == 0 {
+        bail!("piece length should be positive");
+    }
+    let mut candidates_by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    collect_files(search_dir, &mut candidates_by_size)
+        .with_context(|| format!("scan {}", search_dir.display()))?;
+
+    let mut matches: Vec<DataMatch> = vec![];
+    for entry in info.files() {
+        let Some(candidates) = candidates_by_size.get(&entry.length) else {
+            continue;
+        };
+        let pieces = pieces_within(info, &entry);
+        for candidate in candidates {
+            // A candidate we can't read, e.g. for lack of permission, just isn't a match
+            if verify_candidate(info, &entry, &pieces, candidate).unwrap_or(false) {
+                matches.push(DataMatch {
+                    torrent_path: entry.path.clone(),
+                    local_path: candidate.clone(),
+                    verified_pieces: pieces.len(),
+                });
+                break;
+            }
+        }
+    }
+    Ok(matches)
+}
+
+/// Hardlink every matched file to where the torrent expects it under `target_dir`, so the
+/// torrent can be seeded from there.
+pub fn link_data(matches: &[DataMatch], target_dir: &Path) -> Result<()> {
+    for data_match in matches {
+        let target = target_dir.join(sanitize(&data_match.torrent_path).with_context(|| {
+            format!("invalid torrent path {}", data_match.torrent_path.display())
+        })?);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("create directory {}", parent.display()))?;
+        }
+        fs::hard_link(&data_match.local_path, &target).with_context(|| {
+            format!(
+                "link {} to {}",
+                data_match.local_path.display(),
+                target.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+fn collect_files(dir: &Path, files_by_size: &mut HashMap<u64, Vec<PathBuf>>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .context("read directory")?
+        .collect::<std::io::Result<Vec<_>>>()
+        .context("read directory entry")?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        // Symlinks are skipped so a link cycle can't send us around in circles
+        let file_type = entry.file_type().context("get file type")?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files_by_size)?;
+        } else if file_type.is_file() {
+            let size = entry.metadata().context("get file metadata")?.len();
+            files_by_size.entry(size).or_default().push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Indexes of the pieces lying entirely within the given file.
+fn pieces_within(info: &TorrentFileInfo, entry: &TorrentFileEntry) -> Vec<usize> {
+    let file_end = entry.offset + entry.length;
+    let first_piece = entry.offset.div_ceil(info.piece_length) as usize;
+    (first_piece..info.piece_count())
+        .take_while(|&piece_index| {
+            piece_index as u64 * info.piece_length + info.piece_size(piece_index) <= file_end
+        })
+        .collect()
+}
+
+fn verify_candidate(
+    info: &TorrentFileInfo,
+    entry: &TorrentFileEntry,
+    pieces: &[usize],
+    candidate: &Path,
+) -> Result<bool> {
+    let mut file = File::open(candidate).context("open file")?;
+    let mut buf: Vec<u8> = vec![];
+    for &piece_index in pieces {
+        let begin = piece_index as u64 * info.piece_length - entry.offset;
+        buf.resize(info.piece_size(piece_index) as usize, 0);
+        file.seek(SeekFrom::Start(begin)).context("seek to piece")?;
+        file.read_exact(&mut buf)
+            .with_context(|| format!("read #{} piece", piece_index))?;
+
+        let mut hasher = Sha1::new();
+        hasher.update(&buf);
+        if info.piece_hash(piece_index) != Some(&hasher.finalize()[..]) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Only keep the normal components of a path from the torrent, so it can't escape the target
+/// directory.
+fn sanitize(path: &Path) -> Option<PathBuf> {
+    let sanitized: PathBuf = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect();
+    sanitized.components().next().is_some().then_some(sanitized)
+}
@@ -1,10 +1,12 @@
-use anyhow::{Context, Ok, Result};
+use anyhow::{bail, Context, Ok, Result};
 use serde_json::json;
 use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 
 pub fn decode_bencoded_value(encoded_value: &[u8]) -> Result<serde_json::Value> {
     let (_, decoded_value) = decode(encoded_value)?;
-    let json = decoded_value.into_json()?;
+    let json = decoded_value.to_json()?;
     Ok(json)
 }
 
@@ -13,123 +15,477 @@ const ARRAY_START: u8 = b'l';
 const INTEGER_START: u8 = b'i';
 const DICTIONARY_START: u8 = b'd';
 const STRING_SEPARATOR: u8 = b':';
+// Far deeper than any real torrent or extension message, yet shallow enough that a peer can't
+// overflow the stack by nesting lists
+const MAX_DEPTH: usize = 64;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Decoded<'input> {
     String(&'input [u8]),
     Integer(i64),
+    /// An integer kept as its decimal text because it doesn't fit an i64 or isn't written in
+    /// canonical form (e.g. `i-0e`, `i007e`), so it still re-encodes to the same bytes
+    BigInteger(&'input str),
     Array(Vec<Decoded<'input>>),
-    Dictionary(HashMap<String, Decoded<'input>>),
+    Dictionary(Dictionary<&'input [u8], Decoded<'input>>),
+}
+
+/// Same as [`Decoded`] but owning its strings, so it can outlive the input buffer, be sent to
+/// other threads or be cached.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OwnedDecoded {
+    String(Vec<u8>),
+    Integer(i64),
+    BigInteger(String),
+    Array(Vec<OwnedDecoded>),
+    Dictionary(Dictionary<Vec<u8>, OwnedDecoded>),
+}
+
+/// A bencode dictionary that keeps its entries in the order they were decoded or inserted, so
+/// re-encoding reproduces the input, and that keys by raw bytes since nothing guarantees keys
+/// are valid UTF-8. Equality and hashing ignore the order, like a map.
+#[derive(Clone)]
+pub struct Dictionary<K, V> {
+    entries: Vec<(K, V)>,
+    // Position of every key in `entries`, so lookups don't scan them
+    index: HashMap<Box<[u8]>, usize>,
+}
+
+impl<K: AsRef<[u8]>, V> Dictionary<K, V> {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![],
+            index: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&V> {
+        let &position = self.index.get(key.as_ref())?;
+        Some(&self.entries[position].1)
+    }
+
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Insert at the end, or replace the value in place if the key is already present.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.index.get(key.as_ref()) {
+            Some(&position) => Some(std::mem::replace(&mut self.entries[position].1, value)),
+            None => {
+                self.index.insert(key.as_ref().into(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().map(|(_, v)| v)
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for Dictionary<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.entries.iter().map(|(k, v)| (k, v)))
+            .finish()
+    }
+}
+
+impl<K: AsRef<[u8]>, V> Default for Dictionary<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: AsRef<[u8]>, V> FromIterator<(K, V)> for Dictionary<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut dict = Self::new();
+        for (key, value) in iter {
+            dict.insert(key, value);
+        }
+        dict
+    }
+}
+
+impl<K, V> IntoIterator for Dictionary<K, V> {
+    type Item = (K, V);
+    type IntoIter = std::vec::IntoIter<(K, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<K: AsRef<[u8]>, V: PartialEq> PartialEq for Dictionary<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K: AsRef<[u8]>, V: Eq> Eq for Dictionary<K, V> {}
+
+impl<K: AsRef<[u8]>, V: Hash> Hash for Dictionary<K, V> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Hash the entries sorted by key so dictionaries equal up to order hash the same
+        let mut entries: Vec<(&[u8], &V)> = self.iter().map(|(k, v)| (k.as_ref(), v)).collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        entries.hash(state);
+    }
+}
+
+impl<'input> Decoded<'input> {
+    pub fn into_owned(self) -> OwnedDecoded {
+        match self {
+            Decoded::String(bytes) => OwnedDecoded::String(bytes.to_vec()),
+            Decoded::Integer(n) => OwnedDecoded::Integer(n),
+            Decoded::BigInteger(digits) => OwnedDecoded::BigInteger(digits.to_string()),
+            Decoded::Array(arr) => {
+                OwnedDecoded::Array(arr.into_iter().map(Decoded::into_owned).collect())
+            }
+            Decoded::Dictionary(dict) => OwnedDecoded::Dictionary(
+                dict.into_iter()
+                    .map(|(key, value)| (key.to_vec(), value.into_owned()))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dictionary<&'input [u8], Self>> {
+        match self {
+            Decoded::Dictionary(dict) => Some(dict),
+            _ => None,
+        }
+    }
+}
+
+impl OwnedDecoded {
+    /// Borrow this value as a [`Decoded`], e.g. to pass it to code written against the decoder.
+    pub fn as_decoded(&self) -> Decoded<'_> {
+        match self {
+            OwnedDecoded::String(bytes) => Decoded::String(bytes),
+            OwnedDecoded::Integer(n) => Decoded::Integer(*n),
+            OwnedDecoded::BigInteger(digits) => Decoded::BigInteger(digits),
+            OwnedDecoded::Array(arr) => {
+                Decoded::Array(arr.iter().map(OwnedDecoded::as_decoded).collect())
+            }
+            OwnedDecoded::Dictionary(dict) => Decoded::Dictionary(
+                dict.iter()
+                    .map(|(key, value)| (&key[..], value.as_decoded()))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dictionary<Vec<u8>, Self>> {
+        match self {
+            OwnedDecoded::Dictionary(dict) => Some(dict),
+            _ => None,
+        }
+    }
+}
+
+impl<'input> From<Decoded<'input>> for OwnedDecoded {
+    fn from(value: Decoded<'input>) -> Self {
+        value.into_owned()
+    }
+}
+
+impl<'a> From<&'a OwnedDecoded> for Decoded<'a> {
+    fn from(value: &'a OwnedDecoded) -> Self {
+        value.as_decoded()
+    }
+}
+
+// Accessors shared by the borrowed and the owned flavour, so callers can write
+// `dict.get_dict("info")?.get_int("length")` instead of nesting `if let`s.
+macro_rules! impl_accessors {
+    ($ty:ty) => {
+        impl $ty {
+            pub fn as_bytes(&self) -> Option<&[u8]> {
+                match self {
+                    Self::String(bytes) => Some(&bytes[..]),
+                    _ => None,
+                }
+            }
+
+            /// The string as UTF-8, `None` if it isn't a string or isn't valid UTF-8.
+            pub fn as_str(&self) -> Option<&str> {
+                std::str::from_utf8(self.as_bytes()?).ok()
+            }
+
+            /// The integer as an i64, `None` if it isn't an integer or is out of range.
+            pub fn as_int(&self) -> Option<i64> {
+                match self {
+                    Self::Integer(n) => Some(*n),
+                    Self::BigInteger(digits) => digits.parse().ok(),
+                    _ => None,
+                }
+            }
+
+            /// The integer as a u64, `None` if it isn't an integer, is negative or too big.
+            pub fn as_u64(&self) -> Option<u64> {
+                match self {
+                    Self::Integer(n) => u64::try_from(*n).ok(),
+                    Self::BigInteger(digits) => digits.parse().ok(),
+                    _ => None,
+                }
+            }
+
+            /// The decimal text of any integer, big or not.
+            pub fn as_decimal(&self) -> Option<String> {
+                match self {
+                    Self::Integer(n) => Some(n.to_string()),
+                    Self::BigInteger(digits) => Some(digits.to_string()),
+                    _ => None,
+                }
+            }
+
+            pub fn as_list(&self) -> Option<&[Self]> {
+                match self {
+                    Self::Array(arr) => Some(&arr[..]),
+                    _ => None,
+                }
+            }
+
+            /// Look up a key, `None` if this isn't a dictionary or doesn't have the key.
+            pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&Self> {
+                self.as_dict()?.get(key)
+            }
+
+            pub fn get_bytes(&self, key: impl AsRef<[u8]>) -> Option<&[u8]> {
+                self.get(key)?.as_bytes()
+            }
+
+            pub fn get_str(&self, key: impl AsRef<[u8]>) -> Option<&str> {
+                self.get(key)?.as_str()
+            }
+
+            pub fn get_int(&self, key: impl AsRef<[u8]>) -> Option<i64> {
+                self.get(key)?.as_int()
+            }
+
+            pub fn get_u64(&self, key: impl AsRef<[u8]>) -> Option<u64> {
+                self.get(key)?.as_u64()
+            }
+
+            pub fn get_list(&self, key: impl AsRef<[u8]>) -> Option<&[Self]> {
+                self.get(key)?.as_list()
+            }
+
+            /// The value under `key` if it's a dictionary, so lookups can be chained.
+            pub fn get_dict(&self, key: impl AsRef<[u8]>) -> Option<&Self> {
+                self.get(key).filter(|value| value.as_dict().is_some())
+            }
+
+            /// Bencode the value. Dictionaries keep their entry order, so decoding then encoding
+            /// gives back the original bytes.
+            pub fn encode(&self) -> Vec<u8> {
+                let mut buf: Vec<u8> = vec![];
+                self.encode_into(&mut buf);
+                buf
+            }
+
+            pub fn encode_into(&self, buf: &mut Vec<u8>) {
+                match self {
+                    Self::String(bytes) => encode_string(bytes, buf),
+                    Self::Integer(n) => buf.extend(format!("i{}e", n).as_bytes()),
+                    Self::BigInteger(digits) => buf.extend(format!("i{}e", digits).as_bytes()),
+                    Self::Array(arr) => {
+                        buf.push(ARRAY_START);
+                        for item in arr.iter() {
+                            item.encode_into(buf);
+                        }
+                        buf.push(ENDING);
+                    }
+                    Self::Dictionary(dict) => {
+                        buf.push(DICTIONARY_START);
+                        for (key, value) in dict.iter() {
+                            encode_string(key, buf);
+                            value.encode_into(buf);
+                        }
+                        buf.push(ENDING);
+                    }
+                }
+            }
+        }
+    };
+}
+
+impl_accessors!(Decoded<'_>);
+impl_accessors!(OwnedDecoded);
+
+fn encode_string(bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.extend(format!("{}{}", bytes.len(), STRING_SEPARATOR as char).as_bytes());
+    buf.extend(bytes);
 }
 
 type DecodeResult<'input> = Result<(&'input [u8], Decoded<'input>)>;
 
 impl<'input> Decoded<'input> {
-    fn into_json(&self) -> Result<serde_json::Value> {
-        return Ok(match self {
+    fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(match self {
             Decoded::String(bytes) => {
-                json!(std::str::from_utf8(&bytes).context("convert bytes into json string")?)
+                json!(std::str::from_utf8(bytes).context("convert bytes into json string")?)
             }
             Decoded::Integer(n) => json!(n),
+            Decoded::BigInteger(digits) => match digits.parse::<u64>().ok() {
+                Some(n) => json!(n),
+                // JSON numbers can't hold it without losing precision, keep the digits
+                None => json!(digits),
+            },
             Decoded::Array(arr) => {
                 let collected: Result<Vec<serde_json::Value>> =
-                    arr.into_iter().map(|item| item.into_json()).collect();
+                    arr.iter().map(|item| item.to_json()).collect();
                 serde_json::Value::Array(collected.context("collect items into json array")?)
             }
             Decoded::Dictionary(dict) => {
                 let mut map: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
                 for (key, value) in dict.iter() {
                     map.insert(
-                        key.clone(),
-                        value
-                            .into_json()
-                            .context("collect values into json object")?,
+                        std::str::from_utf8(key)
+                            .context("convert key into json string")?
+                            .to_string(),
+                        value.to_json().context("collect values into json object")?,
                     );
                 }
                 serde_json::Value::Object(map)
             }
-        });
+        })
     }
 }
 
-pub fn decode(remaining: &[u8]) -> DecodeResult {
-    Ok(match remaining[0] {
-        ARRAY_START => decode_array(remaining)?,
+pub fn decode(remaining: &[u8]) -> DecodeResult<'_> {
+    decode_nested(remaining, 0)
+}
+
+fn decode_nested(remaining: &[u8], depth: usize) -> DecodeResult<'_> {
+    let Some(&first) = remaining.first() else {
+        bail!("Decoding: unexpected end of input");
+    };
+    if matches!(first, ARRAY_START | DICTIONARY_START) && depth >= MAX_DEPTH {
+        bail!("Decoding: nested deeper than {} levels", MAX_DEPTH);
+    }
+    Ok(match first {
+        ARRAY_START => decode_array(remaining, depth + 1)?,
         INTEGER_START => decode_integer(remaining)?,
-        DICTIONARY_START => decode_dictionary(remaining)?,
+        DICTIONARY_START => decode_dictionary(remaining, depth + 1)?,
         _ => decode_string(remaining)?,
     })
 }
 
-fn decode_array<'input>(remaining: &'input [u8]) -> DecodeResult {
+fn decode_array<'input>(remaining: &'input [u8], depth: usize) -> DecodeResult<'input> {
     // array is encoded as l<inner_encoded_value>e
     //                                           |
     //                                        end_index
     let mut remaining = &remaining[1..];
     let mut items: Vec<Decoded<'input>> = vec![];
     loop {
-        if remaining[0] == ENDING {
-            return Ok((&remaining[1..], Decoded::Array(items)));
+        match remaining.first() {
+            Some(&ENDING) => return Ok((&remaining[1..], Decoded::Array(items))),
+            None => bail!("Decoding Array: missing ending e"),
+            Some(_) => {}
         }
-        let (next_remaining, item) = decode(remaining).context("Decoding Array: parse item")?;
+        let (next_remaining, item) =
+            decode_nested(remaining, depth).context("Decoding Array: parse item")?;
         items.push(item);
         remaining = next_remaining;
     }
 }
 
-fn decode_integer(remaining: &[u8]) -> DecodeResult {
+fn decode_integer(remaining: &[u8]) -> DecodeResult<'_> {
     // integer is encoded as i<number>e
     //                                |
     //                             end_index
-    let mut end_index = 0;
-    while remaining[end_index] != ENDING {
-        end_index += 1;
+    let end_index = remaining
+        .iter()
+        .position(|&b| b == ENDING)
+        .context("Decoding Integer: missing ending e")?;
+    let digits = std::str::from_utf8(&remaining[1..end_index])
+        .context("Decoding Integer: size isn't in valid UTF-8 format")?;
+    let unsigned = digits.strip_prefix('-').unwrap_or(digits);
+    if unsigned.is_empty() || !unsigned.bytes().all(|b| b.is_ascii_digit()) {
+        bail!("Decoding Integer: {:?} isn't a decimal integer", digits);
     }
-    let integer = std::str::from_utf8(&remaining[1..end_index])
-        .context("Decoding Integer: size isn't in valid UTF-8 format")?
-        .parse::<i64>()
-        .context("Decoding Integer: parse size")?;
-    Ok((&remaining[end_index + 1..], Decoded::Integer(integer)))
+    let integer = match digits.parse::<i64>().ok() {
+        // Only canonical forms fit an i64 without changing how they re-encode
+        Some(n) if n.to_string() == digits => Decoded::Integer(n),
+        _ => Decoded::BigInteger(digits),
+    };
+    Ok((&remaining[end_index + 1..], integer))
 }
 
-fn decode_dictionary<'input>(remaining: &'input [u8]) -> DecodeResult {
+fn decode_dictionary<'input>(remaining: &'input [u8], depth: usize) -> DecodeResult<'input> {
     // dictionary is encoded as d<key1><value1>...<keyN><valueN>e
     //                                                          |
     //                                                       end_index
     let mut remaining = &remaining[1..];
-    let mut map: HashMap<String, Decoded<'input>> = HashMap::new();
+    let mut map: Dictionary<&'input [u8], Decoded<'input>> = Dictionary::new();
     loop {
-        if remaining[0] == ENDING {
-            return Ok((&remaining[1..], Decoded::Dictionary(map)));
+        match remaining.first() {
+            Some(&ENDING) => return Ok((&remaining[1..], Decoded::Dictionary(map))),
+            None => bail!("Decoding Dictionary: missing ending e"),
+            Some(_) => {}
         }
         let (next_remaining, key) =
             decode_string(remaining).context("Decoding Dictionary: get key")?;
         remaining = next_remaining;
         let (next_remaining, value) =
-            decode(remaining).context("Decoding Dictionary: parse value")?;
+            decode_nested(remaining, depth).context("Decoding Dictionary: parse value")?;
         remaining = next_remaining;
         if let Decoded::String(key) = key {
-            let key = std::str::from_utf8(key)
-                .context("Decoding Dictionary: key isn't in valid UTF-8 format")?;
-            map.insert(key.to_string(), value);
+            // Merging them would lose an entry, so the dictionary wouldn't re-encode to the input
+            if map.contains_key(key) {
+                bail!(
+                    "Decoding Dictionary: duplicate key {:?}",
+                    String::from_utf8_lossy(key)
+                );
+            }
+            map.insert(key, value);
         }
     }
 }
 
-fn decode_string(remaining: &[u8]) -> DecodeResult {
+fn decode_string(remaining: &[u8]) -> DecodeResult<'_> {
     // string is encoded as <number>:<string>
     //                              |        |
     //                         colon_index   |
     //                                    end_index
-    let mut colon_index = 0;
-    while remaining[colon_index] != STRING_SEPARATOR {
-        colon_index += 1;
-    }
+    let colon_index = remaining
+        .iter()
+        .position(|&b| b == STRING_SEPARATOR)
+        .context("Decoding String: missing separator")?;
+    // Parsing as unsigned rejects negative sizes
     let string_length = std::str::from_utf8(&remaining[..colon_index])
         .context("Decoding String: size isn't in valid UTF-8 format")?
-        .parse::<i64>()
+        .parse::<usize>()
         .context("Decoding String: parse size")?;
-    let end_index = colon_index + 1 + string_length as usize;
+    let available = remaining.len() - colon_index - 1;
+    if string_length > available {
+        bail!(
+            "Decoding String: {} bytes announced but only {} left",
+            string_length,
+            available
+        );
+    }
+    let end_index = colon_index + 1 + string_length;
     Ok((
         &remaining[end_index..],
         Decoded::String(&remaining[colon_index + 1..end_index]),
@@ -1,30 +1,87 @@
-use crate::peer::Peer;
+use crate::announcer::Announcer;
+use crate::async_tracker::track_blocking;
+use crate::bitfield::Bitfield;
+use crate::handshake::HandshakeError;
+use crate::peer::{Peer, PeerError};
+use crate::peer_id::PeerId;
+use crate::peer_listener::PeerListener;
+use crate::peer_message::PeerMessage;
 use crate::torrent_file::TorrentFile;
-use crate::{torrent_file::parse_torrent_file, tracker::track};
+use crate::tracker::{AnnounceEvent, AnnounceParams, TRACKER_TIMEOUT};
 use anyhow::{Context, Error};
-use std::collections::HashMap;
-use std::sync::mpsc::{self, Sender};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::{fs, path::PathBuf};
 
+// Ask the tracker for more peers early when fewer than this are left
+const LOW_PEER_COUNT: usize = 5;
+// Connections to peers open at once, inbound ones included
+const MAX_CONNECTIONS: usize = 20;
+// Peers sending more than this many pieces that don't match their hash are banned
+const BAN_HASH_FAILURES: usize = 3;
+// How long an idle worker waits for a piece before reading what its peer sent meanwhile
+const IDLE_POLL: Duration = Duration::from_millis(50);
+// Idle connections get a keep-alive once nothing was sent on them for this long
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+// Longer block requests from peers are ignored
+const MAX_BLOCK_LENGTH: u32 = 1 << 17;
+
+/// The pieces downloaded and verified so far, by index, shared with the workers uploading them.
+type Pieces = Arc<RwLock<HashMap<usize, Vec<u8>>>>;
+
 pub struct Download;
 
+enum Event {
+    /// A piece download from a peer ended, the peer waits for the next one unless it failed
+    Piece(u32, SocketAddr, Result<Vec<u8>, Error>),
+    /// What a connected peer has, sent before the end of every piece download from it and
+    /// whenever it tells about new pieces while idle
+    Bitfield(SocketAddr, Bitfield),
+    /// An idle peer hung up, or sent something we can't make sense of
+    Disconnected(SocketAddr, Error),
+    /// Bytes of pieces sent to a peer
+    Uploaded(u64),
+    /// The tracker handed out peers on a re-announce
+    Peers(Vec<SocketAddr>),
+    /// A peer connected to us
+    InboundPeer(SocketAddr, Box<Peer>),
+    /// A new connection told who the peer is and what it has, so it can be handed pieces, or it
+    /// failed first
+    Ready(SocketAddr, Result<(PeerId, Bitfield), Error>),
+}
+
 impl Download {
+    /// Download a torrent into a file. Peers connecting to `listener` get a part of the pieces
+    /// too. Without a listener, trackers are told we can't be connected to.
     pub fn download_file(
-        torrent_file_path: &PathBuf,
+        torrent_file: &TorrentFile,
         output_file_path: &PathBuf,
+        listener: Option<&PeerListener>,
     ) -> anyhow::Result<()> {
-        // Read the torrent file to get the tracker URL
-        let contents = fs::read(torrent_file_path).context("open file")?;
-        let torrent_file = parse_torrent_file(&contents[..]).context("parse file")?;
-
-        // Perform the tracker GET request to get a list of peers
-        let track_result = track(&torrent_file).context("track peers")?;
-        let peer_addr_list: Vec<String> = track_result
-            .peer_addr_list
-            .iter()
-            .map(|addr| addr.to_string())
-            .collect();
+        // Tell the tracker we're joining the swarm, and get a list of peers
+        let info_hash = torrent_file.info.hash_info().context("hash info")?;
+        let mut announce_params = AnnounceParams::new(info_hash, torrent_file.info.length);
+        announce_params.port = listener.map_or(0, PeerListener::port);
+        announce_params.event = Some(AnnounceEvent::Started);
+        let track_result =
+            track_blocking(&torrent_file.trackers(), &announce_params, TRACKER_TIMEOUT)
+                .context("track peers")?;
+        announce_params.event = None;
+        for (announce_url, warning) in track_result.warnings() {
+            eprintln!("tracker warning from {}: {}", announce_url, warning);
+        }
+        let mut peer_addr_list: Vec<SocketAddr> = vec![];
+        Self::add_peers(
+            &mut peer_addr_list,
+            track_result
+                .peer_addr_list
+                .iter()
+                .map(|peer_addr| peer_addr.addr),
+        );
 
         // Get how many pieces need to be downloaded
         let hexed_pieces: Vec<String> = torrent_file.info.hex_pieces().context("hex pieces")?;
@@ -36,101 +93,597 @@ impl Download {
             peer_addr_list.len()
         );
 
-        // Start downloading n pieces from m peers
-        let (tx, rx) = mpsc::channel::<(u32, Result<Vec<u8>, Error>)>();
-        let mut peer_idx = 0;
-        for piece_index in 0..piece_count {
-            let peer_addr = peer_addr_list
-                .get(peer_idx % peer_addr_list.len())
-                .with_context(|| format!("get peer address for #{} piece", piece_index))?
-                .clone();
-            peer_idx += 1;
-            let torrent_file = torrent_file.clone();
-            let tx = tx.clone();
-            Self::download_piece(piece_index as u32, peer_addr, torrent_file, tx);
-        }
-
-        let mut all_pieces: HashMap<usize, Vec<u8>> = HashMap::new();
-
-        for received in rx {
-            let (piece_index, piece_data_result) = received;
-            match piece_data_result {
-                Ok(piece_data) => {
+        // Keep asking the trackers that answered for peers while we download
+        let (tx, rx) = mpsc::channel::<Event>();
+        let announcer = Announcers(
+            track_result
+                .responses
+                .iter()
+                .map(|(announce_url, response)| {
+                    let peers_tx = tx.clone();
+                    Announcer::start(
+                        announce_url.clone(),
+                        announce_params.clone(),
+                        response,
+                        move |peer_addr_list| {
+                            let _ = peers_tx.send(Event::Peers(
+                                peer_addr_list
+                                    .iter()
+                                    .map(|peer_addr| peer_addr.addr)
+                                    .collect(),
+                            ));
+                        },
+                    )
+                })
+                .collect(),
+        );
+        let announce_urls: Vec<String> = track_result
+            .responses
+            .into_iter()
+            .map(|(announce_url, _)| announce_url)
+            .collect();
+        if peer_addr_list.len() < LOW_PEER_COUNT {
+            announcer.announce_soon();
+        }
+        if let Some(listener) = listener {
+            let inbound_peers = listener.register(torrent_file).context("accept peers")?;
+            let inbound_tx = tx.clone();
+            // Ends once the torrent is unregistered
+            thread::spawn(move || {
+                for (peer_addr, peer) in inbound_peers {
+                    if inbound_tx
+                        .send(Event::InboundPeer(peer_addr, Box::new(peer)))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+
+        // Start downloading n pieces from m peers, pieces wait while we have no peers at all
+        let mut waiting_pieces: Vec<u32> = (0..piece_count as u32).rev().collect();
+        let all_pieces = Pieces::default();
+        let mut workers = Workers::default();
+        workers.dispatch(
+            &mut waiting_pieces,
+            &mut peer_addr_list,
+            torrent_file,
+            &all_pieces,
+            &tx,
+        );
+
+        for received in &rx {
+            match received {
+                Event::Piece(piece_index, peer_addr, Ok(piece_data)) => {
                     println!("Got #{} piece", piece_index);
-                    all_pieces.insert(piece_index as usize, piece_data);
+                    announcer.record_downloaded(piece_data.len() as u64);
+                    let mut pieces = all_pieces.write().unwrap();
+                    pieces.insert(piece_index as usize, piece_data);
+                    workers.idle.push(peer_addr);
 
                     // All pieces are downloaded, don't need to receive data anymore
-                    if all_pieces.len() == piece_count {
+                    if pieces.len() == piece_count {
                         break;
                     }
                 }
-                Err(_) => {
+                Event::Piece(piece_index, peer_addr, Err(e))
+                    if e.downcast_ref::<PeerError>()
+                        == Some(&PeerError::HashMismatch(piece_index)) =>
+                {
+                    // Another peer gets the piece
+                    waiting_pieces.push(piece_index);
+                    if workers.record_hash_failure(peer_addr, piece_index) {
+                        println!(
+                            "banning peer {}: sent more than #{} pieces failing verification",
+                            peer_addr, BAN_HASH_FAILURES
+                        );
+                        peer_addr_list.retain(|addr| *addr != peer_addr);
+                        if peer_addr_list.len() < LOW_PEER_COUNT {
+                            announcer.announce_soon();
+                        }
+                    } else {
+                        workers.idle.push(peer_addr);
+                    }
+                }
+                Event::Piece(piece_index, peer_addr, Err(e))
+                    if e.downcast_ref::<PeerError>().is_some() =>
+                {
+                    // The peer stays, with another piece it has
+                    workers.idle.push(peer_addr);
+                    waiting_pieces.push(piece_index);
+                }
+                Event::Piece(piece_index, peer_addr, Err(_)) => {
                     // println!("failed to download #{} piece, reschedule...", piece_index);
-                    let peer_addr = peer_addr_list
-                        .get(peer_idx % peer_addr_list.len())
-                        .with_context(|| format!("get peer address for #{} piece", piece_index))?
-                        .clone();
-                    peer_idx += 1;
-                    let torrent_file = torrent_file.clone();
-                    let tx = tx.clone();
-                    Self::download_piece(piece_index, peer_addr, torrent_file, tx);
+                    // The peer is dropped until the tracker hands it out again
+                    workers.connected.remove(&peer_addr);
+                    workers.bitfields.remove(&peer_addr);
+                    peer_addr_list.retain(|addr| *addr != peer_addr);
+                    if peer_addr_list.len() < LOW_PEER_COUNT {
+                        announcer.announce_soon();
+                    }
+                    waiting_pieces.push(piece_index);
+                }
+                Event::Bitfield(peer_addr, bitfield) => {
+                    // Unless we hung up on the peer meanwhile
+                    if workers.connected.contains_key(&peer_addr) {
+                        workers.bitfields.insert(peer_addr, bitfield);
+                    }
+                }
+                Event::Uploaded(bytes) => announcer.record_uploaded(bytes),
+                Event::Disconnected(peer_addr, e) => {
+                    println!("dropping idle peer {}: {:#}", peer_addr, e);
+                    // The peer is dropped until the tracker hands it out again
+                    workers.hang_up(peer_addr);
+                    peer_addr_list.retain(|addr| *addr != peer_addr);
+                    if peer_addr_list.len() < LOW_PEER_COUNT {
+                        announcer.announce_soon();
+                    }
+                }
+                Event::Ready(peer_addr, Ok((peer_id, bitfield))) => {
+                    workers.peer_ids.insert(peer_addr, peer_id);
+                    if workers.is_banned(peer_addr) {
+                        // A banned peer we only recognized once connected
+                        workers.connected.remove(&peer_addr);
+                        peer_addr_list.retain(|addr| *addr != peer_addr);
+                    } else {
+                        workers.bitfields.insert(peer_addr, bitfield);
+                        workers.idle.push(peer_addr);
+                    }
+                }
+                Event::Ready(peer_addr, Err(e)) => {
+                    if let Some(handshake_error) = e.downcast_ref::<HandshakeError>() {
+                        println!("dropping peer {}: {}", peer_addr, handshake_error);
+                    }
+                    // The peer is dropped until the tracker hands it out again
+                    workers.connected.remove(&peer_addr);
+                    peer_addr_list.retain(|addr| *addr != peer_addr);
+                    if peer_addr_list.len() < LOW_PEER_COUNT {
+                        announcer.announce_soon();
+                    }
+                }
+                Event::InboundPeer(peer_addr, peer) => {
+                    match peer.peer_id().client() {
+                        Some(client) => println!("peer {} connected ({})", peer_addr, client),
+                        None => println!("peer {} connected", peer_addr),
+                    }
+                    // Peers beyond our connection limit aren't needed, unless one of the peers
+                    // makes room having none of the pieces still waiting
+                    workers.peer_ids.insert(peer_addr, peer.peer_id());
+                    if !workers.connected.contains_key(&peer_addr)
+                        && !workers.is_banned(peer_addr)
+                        && workers.make_room(&waiting_pieces, &mut peer_addr_list)
+                    {
+                        // Idle once it told what it has, inbound peers often have nothing
+                        workers.connected.insert(
+                            peer_addr,
+                            Self::spawn_worker(
+                                peer_addr,
+                                Some(*peer),
+                                torrent_file,
+                                &all_pieces,
+                                tx.clone(),
+                            ),
+                        );
+                    }
+                }
+                Event::Peers(new_peers) => {
+                    let known_peer_count = peer_addr_list.len();
+                    Self::add_peers(&mut peer_addr_list, new_peers);
+                    if peer_addr_list.len() > known_peer_count {
+                        println!(
+                            "tracker handed out #{} new peers",
+                            peer_addr_list.len() - known_peer_count
+                        );
+                    }
                 }
             }
+            workers.dispatch(
+                &mut waiting_pieces,
+                &mut peer_addr_list,
+                torrent_file,
+                &all_pieces,
+                &tx,
+            );
+        }
+        // Hanging up on every peer
+        drop(workers);
+        let mut announce_params = announcer.stop(announce_params);
+        if let Some(listener) = listener {
+            listener.unregister(&info_hash);
         }
 
         // Aggregate all pieces and output to the target file
         let mut aggregated_data: Vec<u8> = Vec::with_capacity(torrent_file.info.length as usize);
+        let all_pieces = all_pieces.read().unwrap();
         for i in 0..hexed_pieces.len() {
-            let piece = all_pieces.insert(i, vec![]).unwrap();
-            aggregated_data.extend(piece);
+            aggregated_data.extend_from_slice(&all_pieces[&i]);
         }
-        fs::write(&output_file_path, aggregated_data)
+        fs::write(output_file_path, aggregated_data)
             .with_context(|| format!("write the aggregated data to file {:?}", output_file_path))?;
 
+        // We're done and leaving the swarm
+        Self::announce_event(
+            &announce_urls,
+            &mut announce_params,
+            AnnounceEvent::Completed,
+        );
+        Self::announce_event(&announce_urls, &mut announce_params, AnnounceEvent::Stopped);
+
         Ok(())
     }
 
-    fn download_piece(
-        piece_index: u32,
-        peer_addr: String,
-        torrent_file: TorrentFile,
-        tx: Sender<(u32, Result<Vec<u8>, Error>)>,
-    ) -> () {
-        thread::spawn(move || {
-            // println!(
-            //     "trying to download #{} piece from {}",
-            //     piece_index, peer_addr
-            // );
-
-            // Connect to peer
-            let peer = Peer::new(peer_addr.clone(), torrent_file);
-            if peer.is_err() {
-                tx.send((
-                    piece_index,
-                    Err(Error::msg(format!("fail to connect to peer {}", peer_addr))),
-                ))
-                .unwrap();
-                return;
+    fn add_peers(
+        peer_addr_list: &mut Vec<SocketAddr>,
+        new_peers: impl IntoIterator<Item = SocketAddr>,
+    ) {
+        for peer_addr in new_peers {
+            if !peer_addr_list.contains(&peer_addr) {
+                peer_addr_list.push(peer_addr);
+            }
+        }
+    }
+
+    // Failing to report an event doesn't fail the download
+    fn announce_event(
+        announce_urls: &[String],
+        announce_params: &mut AnnounceParams,
+        event: AnnounceEvent,
+    ) {
+        announce_params.event = Some(event);
+        match track_blocking(announce_urls, announce_params, TRACKER_TIMEOUT) {
+            Ok(track_result) => {
+                for (announce_url, e) in track_result.failures {
+                    println!(
+                        "failed to announce {} event to {}: {:#}",
+                        event.as_str(),
+                        announce_url,
+                        e
+                    );
+                }
             }
-            let mut peer = peer.unwrap();
-
-            // Download a piece
-            let piece = peer.download_a_piece(piece_index);
-            if piece.is_err() {
-                tx.send((
-                    piece_index,
-                    Err(Error::msg(format!(
-                        "fail to download #{} piece",
-                        piece_index
-                    ))),
+            Err(e) => println!("failed to announce {} event: {:#}", event.as_str(), e),
+        }
+        announce_params.event = None;
+    }
+
+    // Keep one connection to a peer, downloading the pieces handed over one after the other.
+    // Connects unless the peer is already connected, and is only ready for pieces once the peer
+    // told what it has. Between pieces it keeps reading from the peer, so we learn about the
+    // pieces it gets, and uploads the pieces we have to it. Hangs up when the returned sender is
+    // dropped.
+    fn spawn_worker(
+        peer_addr: SocketAddr,
+        peer: Option<Peer>,
+        torrent_file: &TorrentFile,
+        all_pieces: &Pieces,
+        tx: Sender<Event>,
+    ) -> Sender<u32> {
+        let torrent_file = torrent_file.clone();
+        let all_pieces = all_pieces.clone();
+        let (pieces_tx, pieces_rx) = mpsc::channel::<u32>();
+        thread::spawn(move || {
+            let connected = match peer {
+                Some(peer) => Ok(peer),
+                None => Peer::new(peer_addr, torrent_file)
+                    .with_context(|| format!("fail to connect to peer {}", peer_addr)),
+            };
+            let mut peer = match connected.and_then(|mut peer| {
+                peer.wait_for_availability()?;
+                Ok(peer)
+            }) {
+                Ok(peer) => peer,
+                Err(e) => {
+                    let _ = tx.send(Event::Ready(peer_addr, Err(e)));
+                    return;
+                }
+            };
+            if tx
+                .send(Event::Ready(
+                    peer_addr,
+                    Ok((peer.peer_id(), peer.bitfield().clone())),
                 ))
-                .unwrap();
+                .is_err()
+            {
                 return;
             }
-            let piece = piece.unwrap();
 
-            // Send downloaded piece back to the main thread
-            tx.send((piece_index, Ok(piece))).unwrap();
+            // The pieces the peer knows we have
+            let mut told = Bitfield::new(peer.bitfield().piece_count());
+            let mut last_sent = Instant::now();
+            loop {
+                let (has_new_pieces, uploaded) =
+                    match Self::keep_up_with(&mut peer, &all_pieces, &mut told, &mut last_sent) {
+                        Ok(news) => news,
+                        Err(e) => {
+                            let _ = tx.send(Event::Disconnected(peer_addr, e));
+                            return;
+                        }
+                    };
+                // The download may be over already
+                if (has_new_pieces
+                    && tx
+                        .send(Event::Bitfield(peer_addr, peer.bitfield().clone()))
+                        .is_err())
+                    || (uploaded > 0 && tx.send(Event::Uploaded(uploaded)).is_err())
+                {
+                    return;
+                }
+                let piece_index = match pieces_rx.recv_timeout(IDLE_POLL) {
+                    Ok(piece_index) => piece_index,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                };
+                let piece = peer
+                    .download_a_piece(piece_index)
+                    .with_context(|| format!("fail to download #{} piece", piece_index));
+                last_sent = Instant::now();
+                // Peer errors, about an unavailable piece or a hash mismatch, leave the connection
+                // usable
+                let failed = piece
+                    .as_ref()
+                    .is_err_and(|e| e.downcast_ref::<PeerError>().is_none());
+                // The download may be over already
+                if tx
+                    .send(Event::Bitfield(peer_addr, peer.bitfield().clone()))
+                    .is_err()
+                    || tx
+                        .send(Event::Piece(piece_index, peer_addr, piece))
+                        .is_err()
+                    || failed
+                {
+                    return;
+                }
+            }
         });
+        pieces_tx
+    }
+
+    // Read whatever a peer between pieces sent, tell it about the pieces we got, answer its
+    // requests, and keep the connection alive. Every interested peer is unchoked, there are no
+    // upload slots. Returns whether the peer told about new pieces, and the bytes sent to it.
+    fn keep_up_with(
+        peer: &mut Peer,
+        all_pieces: &Pieces,
+        told: &mut Bitfield,
+        last_sent: &mut Instant,
+    ) -> anyhow::Result<(bool, u64)> {
+        let mut has_new_pieces = false;
+        while let Some(message) = peer.receive_within(Some(Duration::ZERO))? {
+            match message {
+                PeerMessage::Have(_) | PeerMessage::Bitfield(_) => has_new_pieces = true,
+                PeerMessage::Interested if peer.state().am_choking => {
+                    peer.send_message(PeerMessage::Unchoke)?;
+                    *last_sent = Instant::now();
+                }
+                _ => {}
+            }
+        }
+
+        // Copied out, a peer slow to read mustn't hold up storing pieces
+        let mut answers = vec![];
+        {
+            let all_pieces = all_pieces.read().unwrap();
+            for &piece_index in all_pieces.keys() {
+                // Peers having the piece don't need to hear about it
+                if !told.has(piece_index) && !peer.bitfield().has(piece_index) {
+                    answers.push(PeerMessage::Have(piece_index as u32));
+                }
+                told.set(piece_index)?;
+            }
+            for (index, begin, length) in peer.take_requests() {
+                // Requests made while the peer was choked are dropped, as it expects
+                if peer.state().am_choking || length > MAX_BLOCK_LENGTH {
+                    continue;
+                }
+                if let Some(block) = all_pieces.get(&(index as usize)).and_then(|piece| {
+                    piece.get(begin as usize..(begin as usize).checked_add(length as usize)?)
+                }) {
+                    answers.push(PeerMessage::Piece {
+                        index,
+                        begin,
+                        block: block.to_vec(),
+                    });
+                }
+            }
+        }
+        let mut uploaded = 0;
+        for answer in answers {
+            if let PeerMessage::Piece { block, .. } = &answer {
+                uploaded += block.len() as u64;
+            }
+            peer.send_message(answer)?;
+            *last_sent = Instant::now();
+        }
+
+        if last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+            peer.send_message(PeerMessage::KeepAlive)?;
+            *last_sent = Instant::now();
+        }
+        Ok((has_new_pieces, uploaded))
+    }
+}
+
+/// Re-announces to every tracker that answered the first announce.
+struct Announcers(Vec<Announcer>);
+
+impl Announcers {
+    fn announce_soon(&self) {
+        for announcer in &self.0 {
+            announcer.announce_soon();
+        }
+    }
+
+    fn record_downloaded(&self, bytes: u64) {
+        for announcer in &self.0 {
+            announcer.record_downloaded(bytes);
+        }
+    }
+
+    fn record_uploaded(&self, bytes: u64) {
+        for announcer in &self.0 {
+            announcer.record_uploaded(bytes);
+        }
+    }
+
+    // Every announcer gathered the same stats, `announce_params` are only kept without any
+    fn stop(self, announce_params: AnnounceParams) -> AnnounceParams {
+        self.0
+            .into_iter()
+            .map(Announcer::stop)
+            .last()
+            .unwrap_or(announce_params)
+    }
+}
+
+/// The peers we have a connection with, or are connecting to.
+#[derive(Default)]
+struct Workers {
+    connected: HashMap<SocketAddr, Sender<u32>>,
+    /// Connected peers done with their last piece
+    idle: Vec<SocketAddr>,
+    /// What connected peers have, known once they're ready for pieces
+    bitfields: HashMap<SocketAddr, Bitfield>,
+    /// Who answered at each address we were connected to, peers are told apart by IP and id
+    peer_ids: HashMap<SocketAddr, PeerId>,
+    /// Pieces a peer sent that didn't match their hash, once per failure. They're left to other
+    /// peers having them.
+    hash_failures: HashMap<(IpAddr, PeerId), Vec<u32>>,
+    /// Peers that sent too many bad pieces, never handed pieces again whatever port they use
+    banned: HashSet<(IpAddr, PeerId)>,
+}
+
+impl Workers {
+    fn identity(&self, peer_addr: SocketAddr) -> Option<(IpAddr, PeerId)> {
+        self.peer_ids
+            .get(&peer_addr)
+            .map(|peer_id| (peer_addr.ip(), *peer_id))
+    }
+
+    fn is_banned(&self, peer_addr: SocketAddr) -> bool {
+        self.identity(peer_addr)
+            .is_some_and(|identity| self.banned.contains(&identity))
+    }
+
+    fn failed(&self, peer_addr: SocketAddr, piece_index: u32) -> bool {
+        self.identity(peer_addr)
+            .and_then(|identity| self.hash_failures.get(&identity))
+            .is_some_and(|failures| failures.contains(&piece_index))
+    }
+
+    // A peer gets a piece it has, unless it failed its hash check and another peer can send it
+    fn may_get(&self, peer_addr: SocketAddr, piece_index: u32) -> bool {
+        let has = |peer_addr: &SocketAddr| {
+            self.bitfields
+                .get(peer_addr)
+                .is_some_and(|bitfield| bitfield.has(piece_index as usize))
+        };
+        has(&peer_addr)
+            && (!self.failed(peer_addr, piece_index)
+                || !self.bitfields.keys().any(|other| {
+                    *other != peer_addr && has(other) && !self.failed(*other, piece_index)
+                }))
+    }
+
+    fn hang_up(&mut self, peer_addr: SocketAddr) {
+        self.connected.remove(&peer_addr);
+        self.bitfields.remove(&peer_addr);
+        self.idle.retain(|addr| *addr != peer_addr);
+    }
+
+    // True when there's room for one more connection. At the limit, while pieces wait, an idle
+    // peer having none of them is hung up on and dropped until the tracker hands it out again.
+    fn make_room(&mut self, waiting_pieces: &[u32], peer_addr_list: &mut Vec<SocketAddr>) -> bool {
+        if self.connected.len() < MAX_CONNECTIONS {
+            return true;
+        }
+        if waiting_pieces.is_empty() {
+            return false;
+        }
+        let Some(&peer_addr) = self.idle.iter().find(|&&peer_addr| {
+            !waiting_pieces
+                .iter()
+                .any(|&piece_index| self.may_get(peer_addr, piece_index))
+        }) else {
+            return false;
+        };
+        println!(
+            "hanging up on peer {}: has none of the pieces left",
+            peer_addr
+        );
+        self.hang_up(peer_addr);
+        peer_addr_list.retain(|addr| *addr != peer_addr);
+        true
+    }
+
+    // Hangs up on the peer and returns true when it has to be banned
+    fn record_hash_failure(&mut self, peer_addr: SocketAddr, piece_index: u32) -> bool {
+        // Pieces a banned peer was still sending when it got banned don't count
+        let Some(identity) = self
+            .identity(peer_addr)
+            .filter(|identity| !self.banned.contains(identity))
+        else {
+            return false;
+        };
+        let failures = self.hash_failures.entry(identity).or_default();
+        failures.push(piece_index);
+        if failures.len() <= BAN_HASH_FAILURES {
+            return false;
+        }
+        for (peer_addr, peer_id) in &self.peer_ids {
+            if (peer_addr.ip(), *peer_id) == identity {
+                self.connected.remove(peer_addr);
+                self.bitfields.remove(peer_addr);
+            }
+        }
+        self.banned.insert(identity);
+        true
+    }
+
+    // Hand idle peers waiting pieces they have, and connect to known peers for the rest
+    fn dispatch(
+        &mut self,
+        waiting_pieces: &mut Vec<u32>,
+        peer_addr_list: &mut Vec<SocketAddr>,
+        torrent_file: &TorrentFile,
+        all_pieces: &Pieces,
+        tx: &Sender<Event>,
+    ) {
+        let mut still_idle = vec![];
+        while let Some(peer_addr) = self.idle.pop() {
+            // A worker that hung up already is forgotten
+            let Some(pieces_tx) = self.connected.get(&peer_addr) else {
+                continue;
+            };
+            let Some(position) = waiting_pieces
+                .iter()
+                .rposition(|&piece_index| self.may_get(peer_addr, piece_index))
+            else {
+                still_idle.push(peer_addr);
+                continue;
+            };
+            if pieces_tx.send(waiting_pieces[position]).is_ok() {
+                waiting_pieces.remove(position);
+            } else {
+                self.connected.remove(&peer_addr);
+                self.bitfields.remove(&peer_addr);
+            }
+        }
+        self.idle = still_idle;
+
+        // One connection on its way per piece left waiting, they get pieces once they're ready
+        while self.connected.len().saturating_sub(self.bitfields.len()) < waiting_pieces.len() {
+            let Some(&peer_addr) = peer_addr_list.iter().find(|&&peer_addr| {
+                !self.connected.contains_key(&peer_addr) && !self.is_banned(peer_addr)
+            }) else {
+                break;
+            };
+            if !self.make_room(waiting_pieces, peer_addr_list) {
+                break;
+            }
+            let pieces_tx =
+                Download::spawn_worker(peer_addr, None, torrent_file, all_pieces, tx.clone());
+            self.connected.insert(peer_addr, pieces_tx);
+        }
     }
 }
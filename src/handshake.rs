@@ -1,31 +1,159 @@
-#[repr(C)]
-#[repr(packed)]
+use crate::peer_id::PeerId;
+use anyhow::{Context, Result};
+use std::io::{ErrorKind, Read, Write};
+use std::ops::{BitAnd, BitOr};
+
+/// Extensions advertised in the reserved bytes of a handshake, where bit n counts from the right
+/// of the 8 bytes read as a big-endian number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// Extension protocol, needed for e.g. ut_metadata (BEP 10)
+    pub const EXTENSION_PROTOCOL: Self = Self(1 << 20);
+    /// DHT, with a port message telling where the peer's node listens (BEP 5)
+    pub const DHT: Self = Self(1 << 0);
+    /// Fast extension: have all/none, suggest, reject and allowed fast (BEP 6)
+    pub const FAST: Self = Self(1 << 2);
+    /// Upgrade to the v2 protocol for hybrid torrents (BEP 52)
+    pub const V2: Self = Self(1 << 4);
+
+    /// What we advertise to peers.
+    pub fn ours() -> Self {
+        Self::EXTENSION_PROTOCOL
+    }
+
+    pub fn from_reserved_bytes(reserved_bytes: [u8; 8]) -> Self {
+        Self(u64::from_be_bytes(reserved_bytes))
+    }
+
+    pub fn to_reserved_bytes(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// What both sides support, and so may be used on the connection.
+    pub fn negotiate(self, theirs: Self) -> Self {
+        self & theirs
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LENGTH: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum HandshakeError {
+    #[error("handshake is truncated, got {0} of {HANDSHAKE_LENGTH} bytes")]
+    Truncated(usize),
+    #[error("peer speaks another protocol than BitTorrent: {0:?}")]
+    WrongProtocol(String),
+    #[error(
+        "peer answered for info hash {}, expected {}",
+        hex::encode(.got),
+        hex::encode(.expected)
+    )]
+    InfoHashMismatch { expected: [u8; 20], got: [u8; 20] },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake {
-    pub protocol_length: u8,
-    pub protocol: [u8; 19],
     pub reserved_bytes: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
 
-// It's guaranteed to be 68 bytes with repr(C) and repr(packed)
-const HANDSHAKE_SIZE: usize = std::mem::size_of::<Handshake>();
-
 impl Handshake {
-    pub fn new(info_hash: [u8; 20]) -> Self {
+    pub fn new(info_hash: [u8; 20], peer_id: PeerId) -> Self {
         Self {
-            protocol_length: 19,
-            protocol: *b"BitTorrent protocol",
-            reserved_bytes: [0; 8],
+            reserved_bytes: Capabilities::ours().to_reserved_bytes(),
             info_hash,
-            peer_id: *b"00112233445566778899",
+            peer_id: *peer_id.as_bytes(),
         }
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; HANDSHAKE_SIZE];
-        // Safety: Self is a POD with repr(c) and repr(packed)
-        let bytes: &mut [u8; HANDSHAKE_SIZE] = unsafe { &mut *bytes };
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_reserved_bytes(self.reserved_bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LENGTH] {
+        let mut bytes = [0; HANDSHAKE_LENGTH];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved_bytes);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
         bytes
     }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, HandshakeError> {
+        if bytes.len() < HANDSHAKE_LENGTH {
+            return Err(HandshakeError::Truncated(bytes.len()));
+        }
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            // Show what the peer sent as far as it fits in our handshake
+            let protocol_length = (bytes[0] as usize).min(HANDSHAKE_LENGTH - 1);
+            return Err(HandshakeError::WrongProtocol(
+                String::from_utf8_lossy(&bytes[1..1 + protocol_length]).into_owned(),
+            ));
+        }
+        Ok(Self {
+            reserved_bytes: bytes[20..28].try_into().unwrap(),
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..68].try_into().unwrap(),
+        })
+    }
+
+    /// Send our handshake and read the peer's, which has to be for the same torrent.
+    pub fn exchange(&self, stream: &mut (impl Read + Write)) -> Result<Self> {
+        self.write_to(stream).context("send handshake request")?;
+        let theirs = Self::read_from(stream).context("read handshake response")?;
+        if theirs.info_hash != self.info_hash {
+            return Err(HandshakeError::InfoHashMismatch {
+                expected: self.info_hash,
+                got: theirs.info_hash,
+            }
+            .into());
+        }
+        Ok(theirs)
+    }
+
+    pub fn write_to(&self, stream: &mut impl Write) -> Result<()> {
+        stream.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Read a whole handshake, a peer hanging up early makes it [`HandshakeError::Truncated`].
+    pub fn read_from(stream: &mut impl Read) -> Result<Self> {
+        let mut bytes = [0; HANDSHAKE_LENGTH];
+        let mut received = 0;
+        while received < HANDSHAKE_LENGTH {
+            match stream.read(&mut bytes[received..]) {
+                Ok(0) => break,
+                Ok(n) => received += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Self::parse(&bytes[..received])?)
+    }
 }
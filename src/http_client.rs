@@ -0,0 +1,102 @@
+use anyhow::{bail, Context, Result};
+use reqwest::redirect::Policy;
+use reqwest::Proxy;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+static CONFIG: OnceLock<HttpConfig> = OnceLock::new();
+
+/// How we talk HTTP to trackers and other servers: announces, scrapes and .torrent downloads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    /// Send every request through this proxy, e.g. `http://proxy:3128` or `socks5://proxy:1080`
+    pub proxy: Option<String>,
+    pub user_agent: String,
+    pub connect_timeout: Duration,
+    /// How long a request may take. reqwest has no timeout for reads alone, so this one counts
+    /// from the start of the request, connecting included.
+    pub read_timeout: Duration,
+    /// Accept any TLS certificate, only for trackers with self-signed ones
+    pub accept_invalid_certs: bool,
+    pub max_redirects: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            user_agent: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            accept_invalid_certs: false,
+            max_redirects: 10,
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Pick the configuration of the process, which has to happen before the first request.
+    pub fn set(self) -> Result<()> {
+        // Fail early on a proxy URL reqwest can't use
+        if let Some(proxy) = &self.proxy {
+            Proxy::all(proxy).with_context(|| format!("invalid proxy {}", proxy))?;
+        }
+        if CONFIG.set(self).is_err() {
+            bail!("the http configuration is already in use");
+        }
+        Ok(())
+    }
+
+    pub fn get() -> &'static HttpConfig {
+        CONFIG.get_or_init(Default::default)
+    }
+
+    pub fn build_blocking(&self) -> Result<reqwest::blocking::Client> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .user_agent(&self.user_agent)
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.read_timeout)
+            .danger_accept_invalid_certs(self.accept_invalid_certs)
+            .redirect(Policy::limited(self.max_redirects));
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy).context("parse proxy url")?);
+        }
+        builder.build().context("build http client")
+    }
+
+    pub fn build_async(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(&self.user_agent)
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.read_timeout)
+            .danger_accept_invalid_certs(self.accept_invalid_certs)
+            .redirect(Policy::limited(self.max_redirects))
+            // Pooled connections belong to the runtime that opened them, and the client outlives
+            // the runtimes of blocking callers
+            .pool_max_idle_per_host(0);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy).context("parse proxy url")?);
+        }
+        builder.build().context("build http client")
+    }
+}
+
+/// The blocking client of the process, built once from [`HttpConfig::get`].
+pub fn blocking_client() -> Result<&'static reqwest::blocking::Client> {
+    static CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = HttpConfig::get().build_blocking()?;
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+/// The async client of the process, built once from [`HttpConfig::get`].
+pub fn async_client() -> Result<&'static reqwest::Client> {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = HttpConfig::get().build_async()?;
+    Ok(CLIENT.get_or_init(|| client))
+}
@@ -1,6 +1,19 @@
+pub mod announcer;
+pub mod async_tracker;
+pub mod bitfield;
+pub mod cross_seed;
 pub mod decoder;
 pub mod download;
 pub mod handshake;
+pub mod http_client;
+pub mod magnet;
 pub mod peer;
+pub mod peer_id;
+pub mod peer_listener;
+pub mod peer_message;
+pub mod random;
+pub mod source;
 pub mod torrent_file;
 pub mod tracker;
+pub mod tracker_server;
+pub mod udp_tracker;
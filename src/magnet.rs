@@ -0,0 +1,231 @@
+use crate::async_tracker::track_blocking;
+use crate::decoder::decode;
+use crate::handshake::{Capabilities, Handshake};
+use crate::peer_id::PeerId;
+use crate::peer_message::PeerMessage;
+use crate::torrent_file::{parse_info_dictionary, TorrentFile};
+use crate::tracker::{AnnounceParams, TRACKER_TIMEOUT};
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+const MAGNET_PREFIX: &str = "magnet:?";
+const BTIH_PREFIX: &str = "urn:btih:";
+
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+// The id we ask peers to use when they send us ut_metadata messages
+const UT_METADATA_ID: u8 = 1;
+const METADATA_PIECE_SIZE: usize = 1 << 14;
+const MAX_METADATA_SIZE: usize = 16 << 20;
+
+// Announces can't leave `left` out, but before the metadata arrives we don't know the size of
+// the torrent. Rather than make one up we report 0: trackers still hand out peers for it, they
+// only count us as a seeder until our next announce.
+const LEFT_WITHOUT_METADATA: u64 = 0;
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(link: &str) -> Result<Self> {
+        let query = link
+            .strip_prefix(MAGNET_PREFIX)
+            .context("magnet link should start with magnet:?")?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).context("parse magnet link query")?;
+
+        let mut info_hash: Option<[u8; 20]> = None;
+        let mut display_name: Option<String> = None;
+        let mut trackers: Vec<String> = vec![];
+        for (key, value) in params {
+            match key.as_str() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash = Some(decode_info_hash(hash).context("decode btih")?);
+                    }
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.context("magnet link should contain urn:btih")?,
+            display_name,
+            trackers,
+        })
+    }
+
+    /// Ask the peers of every tracker for the info dictionary until one of them hands
+    /// over metadata matching the info hash.
+    pub fn fetch_torrent_file(&self) -> Result<TorrentFile> {
+        if self.trackers.is_empty() {
+            bail!("magnet link has no trackers to find peers with");
+        }
+        let swarm = track_blocking(
+            &self.trackers,
+            &AnnounceParams::new(self.info_hash, LEFT_WITHOUT_METADATA),
+            TRACKER_TIMEOUT,
+        )
+        .context("find peers")?;
+        for peer_addr in &swarm.peer_addr_list {
+            if let Ok(metadata) = fetch_metadata(peer_addr.addr, &self.info_hash) {
+                let mut info = parse_info_dictionary(&metadata).context("parse metadata")?;
+                // The metadata was checked against this hash, whatever keys it holds
+                info.info_hash = Some(self.info_hash);
+                // Every tracker of the link is kept, even those that didn't answer this time
+                return Ok(TorrentFile {
+                    announce: self.trackers[0].clone(),
+                    announce_list: self
+                        .trackers
+                        .iter()
+                        .map(|tracker| vec![tracker.clone()])
+                        .collect(),
+                    info,
+                });
+            }
+        }
+        bail!(
+            "no peer could provide the metadata of {}",
+            hex::encode(self.info_hash)
+        )
+    }
+}
+
+fn decode_info_hash(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("info hash isn't valid hex")?,
+        32 => decode_base32(hash).context("info hash isn't valid base32")?,
+        n => bail!(
+            "info hash should be 40 hex or 32 base32 characters, got {}",
+            n
+        ),
+    };
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("info hash should be 20 bytes"))
+}
+
+fn decode_base32(encoded: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut buffered_bits = 0;
+    for c in encoded.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            upper @ b'A'..=b'Z' => upper - b'A',
+            digit @ b'2'..=b'7' => digit - b'2' + 26,
+            _ => bail!("invalid base32 character {:?}", c as char),
+        };
+        buffer = (buffer << 5) | value as u32;
+        buffered_bits += 5;
+        if buffered_bits >= 8 {
+            buffered_bits -= 8;
+            bytes.push((buffer >> buffered_bits) as u8);
+            buffer &= (1 << buffered_bits) - 1;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Download the info dictionary from a single peer with the ut_metadata extension (BEP 9).
+pub fn fetch_metadata(peer_addr: SocketAddr, info_hash: &[u8; 20]) -> Result<Vec<u8>> {
+    let mut stream =
+        TcpStream::connect_timeout(&peer_addr, PEER_TIMEOUT).context("connect to peer")?;
+    stream
+        .set_read_timeout(Some(PEER_TIMEOUT))
+        .context("set read timeout")?;
+
+    // Our handshake advertises the extension protocol
+    let handshake = Handshake::new(*info_hash, PeerId::session())
+        .exchange(&mut stream)
+        .context("exchange handshakes")?;
+    if !handshake
+        .capabilities()
+        .contains(Capabilities::EXTENSION_PROTOCOL)
+    {
+        bail!("peer doesn't support the extension protocol");
+    }
+
+    // Exchange extension handshakes to learn the peer's ut_metadata id and the metadata size
+    PeerMessage::Extended {
+        id: EXTENDED_HANDSHAKE_ID,
+        payload: format!("d1:md11:ut_metadatai{}eee", UT_METADATA_ID).into_bytes(),
+    }
+    .write_to(&mut stream)
+    .context("send extension handshake")?;
+    let extension_handshake = wait_extended_message(&mut stream, EXTENDED_HANDSHAKE_ID)
+        .context("wait extension handshake")?;
+    let extension_handshake = decode(&extension_handshake)
+        .context("decode extension handshake")?
+        .1;
+    let peer_ut_metadata_id = extension_handshake
+        .get_dict("m")
+        .and_then(|m| m.get_int("ut_metadata"))
+        .and_then(|n| u8::try_from(n).ok());
+    let metadata_size = extension_handshake
+        .get_int("metadata_size")
+        .and_then(|n| usize::try_from(n).ok());
+    let peer_ut_metadata_id = peer_ut_metadata_id
+        .filter(|id| *id != 0)
+        .context("peer doesn't support ut_metadata")?;
+    let metadata_size = metadata_size
+        .filter(|size| *size > 0 && *size <= MAX_METADATA_SIZE)
+        .context("peer sent no usable metadata_size")?;
+
+    // Request the metadata piece by piece
+    let mut metadata: Vec<u8> = Vec::with_capacity(metadata_size);
+    for piece in 0..metadata_size.div_ceil(METADATA_PIECE_SIZE) {
+        PeerMessage::Extended {
+            id: peer_ut_metadata_id,
+            payload: format!("d8:msg_typei0e5:piecei{}ee", piece).into_bytes(),
+        }
+        .write_to(&mut stream)
+        .with_context(|| format!("send #{} metadata request", piece))?;
+
+        let response = wait_extended_message(&mut stream, UT_METADATA_ID)
+            .with_context(|| format!("wait #{} metadata piece", piece))?;
+        let (data, header) = decode(&response).context("decode metadata message")?;
+        let msg_type = header.get_int("msg_type");
+        let piece_index = header.get_int("piece");
+        match msg_type {
+            Some(1) if piece_index == Some(piece as i64) => metadata.extend(data),
+            Some(2) => bail!("peer rejected #{} metadata request", piece),
+            _ => bail!("unexpected answer to #{} metadata request", piece),
+        }
+    }
+    if metadata.len() != metadata_size {
+        bail!(
+            "received {} bytes of metadata, expected {}",
+            metadata.len(),
+            metadata_size
+        );
+    }
+
+    let mut hasher = Sha1::new();
+    hasher.update(&metadata);
+    let hash: [u8; 20] = hasher.finalize().into();
+    if hash != *info_hash {
+        bail!("metadata doesn't match the info hash");
+    }
+
+    Ok(metadata)
+}
+
+/// Skip messages until an extended message with the given extended id arrives, and return its
+/// payload without the id.
+fn wait_extended_message(stream: &mut TcpStream, extended_id: u8) -> Result<Vec<u8>> {
+    loop {
+        if let PeerMessage::Extended { id, payload } = PeerMessage::read_from(stream)? {
+            if id == extended_id {
+                return Ok(payload);
+            }
+        }
+    }
+}
@@ -1,114 +1,267 @@
 use anyhow::{Context, Ok, Result};
+use bittorrent_starter_rust::cross_seed::{find_data, link_data};
 use bittorrent_starter_rust::decoder::decode_bencoded_value;
 use bittorrent_starter_rust::download::Download;
 use bittorrent_starter_rust::handshake::Handshake;
-use bittorrent_starter_rust::peer::Peer;
-use bittorrent_starter_rust::torrent_file::parse_torrent_file;
-use bittorrent_starter_rust::tracker::track;
+use bittorrent_starter_rust::http_client::HttpConfig;
+use bittorrent_starter_rust::peer::{Peer, PipelineConfig};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_listener::PeerListener;
+use bittorrent_starter_rust::source::TorrentSource;
+use bittorrent_starter_rust::tracker::{scrape, track};
+use bittorrent_starter_rust::tracker_server::{TrackerConfig, TrackerServer};
 use clap::{Parser, Subcommand};
+use std::collections::HashMap;
 use std::fs;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
-use std::path::PathBuf;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 struct Args {
+    /// Our peer id, a shorter prefix is filled up with random characters [default: -BS0001- and
+    /// random characters]
+    #[arg(long, global = true, allow_hyphen_values = true)]
+    peer_id: Option<PeerId>,
+    #[command(flatten)]
+    http: HttpArgs,
+    #[command(flatten)]
+    pipeline: PipelineArgs,
     #[command(subcommand)]
     command: Command,
 }
+
+/// How HTTP requests to trackers are made
+#[derive(clap::Args, Debug)]
+struct HttpArgs {
+    /// Send HTTP requests through this proxy, e.g. http://proxy:3128
+    #[arg(long, global = true)]
+    proxy: Option<String>,
+    /// User agent of HTTP requests [default: bittorrent-starter-rust/<version>]
+    #[arg(long, global = true)]
+    user_agent: Option<String>,
+    /// Seconds to wait for an HTTP connection
+    #[arg(long, global = true, default_value_t = 10)]
+    connect_timeout: u64,
+    /// Seconds to wait for a whole HTTP response, connecting included
+    #[arg(long, global = true, default_value_t = 30)]
+    read_timeout: u64,
+    /// Accept invalid TLS certificates, e.g. self-signed ones
+    #[arg(long, global = true)]
+    insecure: bool,
+    #[arg(long, global = true, default_value_t = 10)]
+    max_redirects: usize,
+}
+
+impl HttpArgs {
+    fn into_config(self) -> HttpConfig {
+        let default = HttpConfig::default();
+        HttpConfig {
+            proxy: self.proxy,
+            user_agent: self.user_agent.unwrap_or(default.user_agent),
+            connect_timeout: Duration::from_secs(self.connect_timeout),
+            read_timeout: Duration::from_secs(self.read_timeout),
+            accept_invalid_certs: self.insecure,
+            max_redirects: self.max_redirects,
+        }
+    }
+}
+
+/// How many blocks are requested from a peer at once
+#[derive(clap::Args, Debug)]
+struct PipelineArgs {
+    /// Requests outstanding with a new peer, and the fewest with a slow one
+    #[arg(long, global = true, default_value_t = 4)]
+    min_requests: usize,
+    /// Requests outstanding with the fastest peers
+    #[arg(long, global = true, default_value_t = 64)]
+    max_requests: usize,
+    /// Seconds to wait for a requested block before giving the piece to another peer
+    #[arg(long, global = true, default_value_t = 30)]
+    request_timeout: u64,
+}
+
+impl PipelineArgs {
+    fn into_config(self) -> PipelineConfig {
+        PipelineConfig {
+            min_requests: self.min_requests,
+            max_requests: self.max_requests,
+            request_timeout: Duration::from_secs(self.request_timeout),
+        }
+    }
+}
+
 #[derive(Debug, Subcommand)]
 enum Command {
     Decode {
         encoded_value: String,
     },
     Info {
-        file_path: PathBuf,
+        source: TorrentSource,
     },
     Peers {
-        file_path: PathBuf,
+        source: TorrentSource,
     },
     Handshake {
-        file_path: PathBuf,
+        source: TorrentSource,
         peer: SocketAddr,
     },
     #[command(name = "download_piece")]
     DownloadPiece {
         #[arg(short)]
         output_file_path: PathBuf,
-        file_path: PathBuf,
+        source: TorrentSource,
         piece_index: u32,
     },
+    /// Download a torrent to the output file, or a directory of torrents into the output directory
     Download {
         #[arg(short)]
         output_file_path: PathBuf,
-        file_path: PathBuf,
+        source: TorrentSource,
+        /// Accept connections from peers on this port, 0 picks a free one
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+    },
+    /// Show the seeders, leechers and completed downloads of torrents without announcing
+    Scrape {
+        source: TorrentSource,
+        /// Print the statistics as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run services for other BitTorrent clients
+    Tracker {
+        #[command(subcommand)]
+        command: TrackerCommand,
+    },
+    /// Find local files holding a torrent's data so it can be seeded without downloading
+    #[command(name = "find-data")]
+    FindData {
+        source: TorrentSource,
+        search_dir: PathBuf,
+        /// Hardlink the matched files into this directory using the torrent's layout
+        #[arg(long)]
+        link: Option<PathBuf>,
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum TrackerCommand {
+    /// Serve an in-memory HTTP tracker on /announce and /scrape
+    Serve {
+        #[arg(long, default_value = "0.0.0.0:6969")]
+        listen: SocketAddr,
+        /// How often peers should announce, in seconds
+        #[arg(long, default_value_t = 1800)]
+        interval: u64,
+        /// Only track this info hash, in hex, may be repeated [default: track any]
+        #[arg(long = "allow", value_parser = parse_info_hash)]
+        allowed_info_hashes: Vec<[u8; 20]>,
+    },
+}
+
+fn parse_info_hash(s: &str) -> Result<[u8; 20]> {
+    hex::decode(s)
+        .context("info hash isn't valid hex")?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("info hash should be 20 bytes"))
+}
+
 fn main() -> Result<()> {
-    match Args::parse().command {
+    let args = Args::parse();
+    if let Some(peer_id) = args.peer_id {
+        PeerId::set_session(peer_id).context("set peer id")?;
+    }
+    args.http
+        .into_config()
+        .set()
+        .context("configure http client")?;
+    args.pipeline
+        .into_config()
+        .set()
+        .context("configure request pipelining")?;
+    match args.command {
         Command::Decode { encoded_value } => {
             let decoded_value =
                 decode_bencoded_value(encoded_value.as_bytes()).context("decode value")?;
-            println!("{}", decoded_value.to_string());
+            println!("{}", decoded_value);
         }
-        Command::Info { file_path } => {
-            let contents = fs::read(file_path).context("open file")?;
-            let torrent_file = parse_torrent_file(&contents[..]).context("parse file")?;
-            println!("Tracker URL: {}", torrent_file.announce);
-            println!("Length: {}", torrent_file.info.length);
-            println!(
-                "Info Hash: {}",
-                torrent_file.info.hex_info().context("hash info")?
-            );
-            println!("Piece Length: {}", torrent_file.info.piece_length);
-            println!("Piece Hashes");
-            for s in torrent_file.info.hex_pieces().context("hex pieces")? {
-                println!("{}", s);
+        Command::Info { source } => {
+            let torrent_files = source
+                .load()
+                .with_context(|| format!("load torrents from {}", source))?;
+            for (i, torrent_file) in torrent_files.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                println!("Tracker URL: {}", torrent_file.announce);
+                println!("Length: {}", torrent_file.info.length);
+                println!(
+                    "Info Hash: {}",
+                    torrent_file.info.hex_info().context("hash info")?
+                );
+                println!("Piece Length: {}", torrent_file.info.piece_length);
+                println!("Piece Hashes");
+                for s in torrent_file.info.hex_pieces().context("hex pieces")? {
+                    println!("{}", s);
+                }
             }
         }
-        Command::Peers { file_path } => {
-            let contents = fs::read(file_path).context("open file")?;
-            let torrent_file = parse_torrent_file(&contents[..]).context("parse file")?;
-            let track_result = track(&torrent_file).context("track peers")?;
-            for peer_addr in track_result.peer_addr_list {
-                println!("{}", peer_addr.to_string());
+        Command::Peers { source } => {
+            let torrent_files = source
+                .load()
+                .with_context(|| format!("load torrents from {}", source))?;
+            for (i, torrent_file) in torrent_files.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                let track_result = track(torrent_file).context("track peers")?;
+                for (announce_url, warning) in track_result.warnings() {
+                    eprintln!("tracker warning from {}: {}", announce_url, warning);
+                }
+                for peer_addr in track_result.peer_addr_list {
+                    match peer_addr.client() {
+                        Some(client) => println!("{} ({})", peer_addr, client),
+                        None => println!("{}", peer_addr),
+                    }
+                }
             }
         }
-        Command::Handshake { file_path, peer } => {
-            let contents = fs::read(file_path).context("open file")?;
-            let torrent_file = parse_torrent_file(&contents[..]).context("parse file")?;
+        Command::Handshake { source, peer } => {
+            let torrent_file = source
+                .load_one()
+                .with_context(|| format!("load torrent from {}", source))?;
             let info_hash = torrent_file.info.hash_info().context("hash info")?;
             let mut stream = TcpStream::connect(peer).context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash);
-            let handshake_bytes = handshake.as_bytes_mut();
-            stream
-                .write(handshake_bytes)
-                .context("send handshake request")?;
-            stream
-                .read_exact(handshake_bytes)
-                .context("read handshake response")?;
-            assert_eq!(handshake.protocol_length, 19);
-            assert_eq!(&handshake.protocol, b"BitTorrent protocol");
-            assert_eq!(handshake.info_hash, info_hash);
-            println!("Peer ID: {}", hex::encode(&handshake.peer_id));
+            let handshake = Handshake::new(info_hash, PeerId::session())
+                .exchange(&mut stream)
+                .context("exchange handshakes")?;
+            println!("Peer ID: {}", hex::encode(handshake.peer_id));
+            if let Some(client) = PeerId(handshake.peer_id).client() {
+                println!("Peer Client: {}", client);
+            }
         }
         Command::DownloadPiece {
             output_file_path,
-            file_path,
+            source,
             piece_index,
         } => {
             // Read the torrent file to get the tracker URL
-            let contents = fs::read(file_path).context("open file")?;
-            let torrent_file = parse_torrent_file(&contents[..]).context("parse file")?;
+            let torrent_file = source
+                .load_one()
+                .with_context(|| format!("load torrent from {}", source))?;
 
-            // Perform the tracker GET request to get a list of peers
+            // Ask every tracker for a list of peers
             let track_result = track(&torrent_file).context("track peers")?;
+            for (announce_url, warning) in track_result.warnings() {
+                eprintln!("tracker warning from {}: {}", announce_url, warning);
+            }
             let first_peer_addr = track_result
                 .peer_addr_list
                 .first()
                 .context("get first peer")?
-                .to_string();
+                .addr;
 
             // Download the piece from the first peer
             let mut peer = Peer::new(first_peer_addr, torrent_file).context("create peer")?;
@@ -129,10 +282,153 @@ fn main() -> Result<()> {
         }
         Command::Download {
             output_file_path,
-            file_path,
+            source,
+            port,
+        } => {
+            // Downloading still works when nobody can connect to us
+            let listener = PeerListener::bind_any(port)
+                .inspect_err(|e| eprintln!("not accepting peers on port {}: {:#}", port, e))
+                .ok();
+            if source.is_batch() {
+                fs::create_dir_all(&output_file_path).context("create output directory")?;
+                for torrent_file in source
+                    .load()
+                    .with_context(|| format!("load torrents from {}", source))?
+                {
+                    // Only keep the last path component so a crafted name can't escape the
+                    // output directory
+                    let file_name = Path::new(&torrent_file.info.name)
+                        .file_name()
+                        .with_context(|| format!("invalid name {:?}", torrent_file.info.name))?;
+                    let output_file_path = output_file_path.join(file_name);
+                    Download::download_file(&torrent_file, &output_file_path, listener.as_ref())
+                        .with_context(|| {
+                            format!(
+                                "download {} to {:?}",
+                                torrent_file.info.name, output_file_path
+                            )
+                        })?;
+                }
+            } else {
+                let torrent_file = source
+                    .load_one()
+                    .with_context(|| format!("load torrent from {}", source))?;
+                Download::download_file(&torrent_file, &output_file_path, listener.as_ref())
+                    .with_context(|| format!("download {} to {:?}", source, output_file_path))?;
+            }
+        }
+        Command::Scrape { source, json } => {
+            let torrent_files = source
+                .load()
+                .with_context(|| format!("load torrents from {}", source))?;
+
+            // Scrape each tracker once for all of its torrents
+            let mut info_hashes_by_tracker: Vec<(&str, Vec<[u8; 20]>)> = vec![];
+            for torrent_file in &torrent_files {
+                let info_hash = torrent_file.info.hash_info().context("hash info")?;
+                match info_hashes_by_tracker
+                    .iter_mut()
+                    .find(|(announce, _)| *announce == torrent_file.announce)
+                {
+                    Some((_, info_hashes)) => info_hashes.push(info_hash),
+                    None => info_hashes_by_tracker.push((&torrent_file.announce, vec![info_hash])),
+                }
+            }
+            let mut stats = HashMap::new();
+            for (announce, info_hashes) in info_hashes_by_tracker {
+                // One unreachable tracker shouldn't hide the others' statistics
+                match scrape(announce, &info_hashes) {
+                    Result::Ok(tracker_stats) => stats.extend(tracker_stats),
+                    Err(e) => eprintln!("failed to scrape {}: {:#}", announce, e),
+                }
+            }
+
+            let mut rows = vec![];
+            for torrent_file in &torrent_files {
+                let info_hash = torrent_file.info.hash_info().context("hash info")?;
+                let torrent_stats = stats.get(&info_hash);
+                if json {
+                    rows.push(serde_json::json!({
+                        "name": torrent_file.info.name,
+                        "info_hash": hex::encode(info_hash),
+                        "tracker": torrent_file.announce,
+                        "stats": torrent_stats,
+                    }));
+                    continue;
+                }
+                match torrent_stats {
+                    Some(torrent_stats) => println!(
+                        "{} ({}): {} seeders, {} leechers, {} completed",
+                        torrent_file.info.name,
+                        hex::encode(info_hash),
+                        torrent_stats.complete,
+                        torrent_stats.incomplete,
+                        torrent_stats.downloaded
+                    ),
+                    None => println!(
+                        "{} ({}): no statistics",
+                        torrent_file.info.name,
+                        hex::encode(info_hash)
+                    ),
+                }
+            }
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&rows).context("serialize statistics")?
+                );
+            }
+        }
+        Command::Tracker {
+            command:
+                TrackerCommand::Serve {
+                    listen,
+                    interval,
+                    allowed_info_hashes,
+                },
         } => {
-            Download::download_file(&file_path, &output_file_path)
-                .with_context(|| format!("download {:?} to {:?}", file_path, output_file_path))?;
+            let interval = Duration::from_secs(interval);
+            let tracker = TrackerServer::new(TrackerConfig {
+                interval,
+                // Give peers a missed announce before forgetting them
+                peer_timeout: interval * 2,
+                whitelist: (!allowed_info_hashes.is_empty())
+                    .then(|| allowed_info_hashes.into_iter().collect()),
+            });
+            let listener =
+                TcpListener::bind(listen).with_context(|| format!("listen on {}", listen))?;
+            println!("tracker listening on http://{}/announce", listen);
+            Arc::new(tracker).serve(listener)?;
+        }
+        Command::FindData {
+            source,
+            search_dir,
+            link,
+        } => {
+            for torrent_file in source
+                .load()
+                .with_context(|| format!("load torrents from {}", source))?
+            {
+                let matches = find_data(&torrent_file.info, &search_dir)
+                    .with_context(|| format!("find data of {}", torrent_file.info.name))?;
+                for entry in torrent_file.info.files() {
+                    match matches.iter().find(|m| m.torrent_path == entry.path) {
+                        Some(m) if m.verified_pieces == 0 => println!(
+                            "{} -> {} (size only)",
+                            m.torrent_path.display(),
+                            m.local_path.display()
+                        ),
+                        Some(m) => {
+                            println!("{} -> {}", m.torrent_path.display(), m.local_path.display())
+                        }
+                        None => eprintln!("{}: no matching data found", entry.path.display()),
+                    }
+                }
+                if let Some(target_dir) = &link {
+                    link_data(&matches, target_dir)
+                        .with_context(|| format!("link data into {}", target_dir.display()))?;
+                }
+            }
         }
     }
     Ok(())
@@ -1,261 +1,428 @@
-use crate::handshake::Handshake;
+use crate::bitfield::Bitfield;
+use crate::handshake::{Capabilities, Handshake};
+use crate::peer_id::PeerId;
+use crate::peer_message::PeerMessage;
 use crate::torrent_file::TorrentFile;
-use anyhow::{Context, Ok, Result};
-use bytes::{BufMut, BytesMut};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use anyhow::{bail, Context, Ok, Result};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum MessageTag {
-    Choke = 0,
-    Unchoke = 1,
-    Interested = 2,
-    NotInterested = 3,
-    Have = 4,
-    Bitfield = 5,
-    Request = 6,
-    Piece = 7,
-    Cancel = 8,
-}
+const BLOCK_SIZE: u32 = 1 << 14;
+// Keep enough requests outstanding for the peer to be busy this long
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+// Requests from the peer waiting for an answer, later ones are dropped
+const MAX_PEER_REQUESTS: usize = 256;
 
-impl From<u8> for MessageTag {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => MessageTag::Choke,
-            1 => MessageTag::Unchoke,
-            2 => MessageTag::Interested,
-            3 => MessageTag::NotInterested,
-            4 => MessageTag::Have,
-            5 => MessageTag::Bitfield,
-            6 => MessageTag::Request,
-            7 => MessageTag::Piece,
-            8 => MessageTag::Cancel,
-            _ => unreachable!(),
-        }
-    }
-}
+static PIPELINE: OnceLock<PipelineConfig> = OnceLock::new();
 
-#[derive(Debug, Clone)]
-pub struct Message {
-    pub tag: MessageTag,
-    pub payload: Vec<u8>,
+/// How many block requests are kept outstanding with a peer, and for how long.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PipelineConfig {
+    /// Outstanding requests with a peer we don't know the speed of yet, and the fewest we keep
+    pub min_requests: usize,
+    pub max_requests: usize,
+    /// A request not answered in this time fails the piece, so that another peer gets it
+    pub request_timeout: Duration,
 }
 
-#[repr(C)]
-#[repr(packed)]
-pub struct Request {
-    pub index: [u8; 4],
-    pub begin: [u8; 4],
-    pub length: [u8; 4],
-}
-
-impl Request {
-    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+impl Default for PipelineConfig {
+    fn default() -> Self {
         Self {
-            index: index.to_be_bytes(),
-            begin: begin.to_be_bytes(),
-            length: length.to_be_bytes(),
+            min_requests: 4,
+            max_requests: 64,
+            request_timeout: Duration::from_secs(30),
         }
     }
+}
 
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
-    }
-
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
-    }
-
-    pub fn length(&self) -> u32 {
-        u32::from_be_bytes(self.length)
+impl PipelineConfig {
+    /// Pick the configuration of the process, which has to happen before the first connection.
+    pub fn set(self) -> Result<()> {
+        if self.min_requests == 0 {
+            bail!("at least one request has to be outstanding");
+        }
+        if self.min_requests > self.max_requests {
+            bail!(
+                "minimum of {} outstanding requests is above the maximum of {}",
+                self.min_requests,
+                self.max_requests
+            );
+        }
+        if PIPELINE.set(self).is_err() {
+            bail!("the pipeline configuration is already in use");
+        }
+        Ok(())
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        // Safety: Self is a POD with repr(c) and repr(packed)
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
-        bytes
+    pub fn get() -> &'static PipelineConfig {
+        PIPELINE.get_or_init(Default::default)
     }
 }
 
-#[repr(C)]
-// NOTE: needs to be (and is)
-// #[repr(packed)]
-// but can't be marked as such because of the T: ?Sized part
-pub struct Piece<T: ?Sized = [u8]> {
-    index: [u8; 4],
-    begin: [u8; 4],
-    block: T,
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum PeerError {
+    /// The connection is fine, the piece has to come from another peer: this one doesn't have it
+    /// or keeps us choked
+    #[error("peer doesn't have #{0} piece, or keeps us choked")]
+    PieceUnavailable(u32),
+    /// The peer sent a piece that doesn't match the torrent, which is nothing to keep
+    #[error("#{0} piece doesn't match its hash")]
+    HashMismatch(u32),
 }
 
-impl Piece {
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
-    }
-
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
-    }
-
-    pub fn block(&self) -> &[u8] {
-        &self.block
-    }
+/// Who lets whom download on a connection. Both sides start out choking and not interested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerState {
+    /// We don't answer the peer's requests
+    pub am_choking: bool,
+    /// We want pieces from the peer
+    pub am_interested: bool,
+    /// The peer doesn't answer our requests, and dropped those it didn't answer yet
+    pub peer_choking: bool,
+    /// The peer wants pieces from us
+    pub peer_interested: bool,
+}
 
-    const PIECE_LEAD: usize = std::mem::size_of::<Piece<()>>();
-    pub fn ref_from_bytes(data: &[u8]) -> Option<&Self> {
-        if data.len() < Self::PIECE_LEAD {
-            return None;
+impl Default for PeerState {
+    fn default() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
         }
-        let n = data.len();
-        // NOTE: The slicing here looks really weird. The reason we do it is because we need the
-        // length part of the fat pointer to Piece to hold the length of _just_ the `block` field.
-        // And the only way we can change the length of the fat pointer to Piece is by changing the
-        // length of the fat pointer to the slice, which we do by slicing it. We can't slice it at
-        // the front (as it would invalidate the ptr part of the fat pointer), so we slice it at
-        // the back!
-        let piece = &data[..n - Self::PIECE_LEAD] as *const [u8] as *const Piece;
-        // Safety: Piece is a POD with repr(c) and repr(packed), _and_ the fat pointer data length
-        // is the length of the trailing DST field (thanks to the PIECE_LEAD offset).
-        Some(unsafe { &*piece })
     }
 }
 
 pub struct Peer {
     torrent_file: TorrentFile,
     stream: TcpStream,
+    capabilities: Capabilities,
+    peer_id: PeerId,
+    state: PeerState,
+    /// The pieces the peer has, as in its bitfield message and the have messages since
+    bitfield: Bitfield,
+    pipeline: PipelineConfig,
+    queue_depth: usize,
+    /// Blocks the peer asked us for, as (index, begin, length)
+    peer_requests: Vec<(u32, u32, u32)>,
 }
 
 impl Peer {
-    pub fn new(peer_addr: String, torrent_file: TorrentFile) -> Result<Self> {
+    pub fn new(peer_addr: SocketAddr, torrent_file: TorrentFile) -> Result<Self> {
         let info_hash = torrent_file.info.hash_info().context("hash info")?;
 
         // Establish a TCP connection with a peer, and perform a handshake
         let mut stream = TcpStream::connect(peer_addr).context("connect to peer")?;
-        let mut handshake = Handshake::new(info_hash);
-        let handshake_bytes = handshake.as_bytes_mut();
-        stream
-            .write(handshake_bytes)
-            .context("send handshake request")?;
-        stream
-            .read_exact(handshake_bytes)
-            .context("read handshake response")?;
-        assert_eq!(handshake.protocol_length, 19);
-        assert_eq!(&handshake.protocol, b"BitTorrent protocol");
-        assert_eq!(handshake.info_hash, info_hash);
-
-        Ok(Self {
+        let ours = Handshake::new(info_hash, PeerId::session());
+        let theirs = ours.exchange(&mut stream).context("exchange handshakes")?;
+
+        Ok(Self::from_stream(
+            stream,
+            torrent_file,
+            ours.capabilities().negotiate(theirs.capabilities()),
+            theirs.peer_id.into(),
+        ))
+    }
+
+    /// Take over a connection whose handshakes were already exchanged, e.g. an inbound one.
+    pub fn from_stream(
+        stream: TcpStream,
+        torrent_file: TorrentFile,
+        capabilities: Capabilities,
+        peer_id: PeerId,
+    ) -> Self {
+        let piece_count = torrent_file.info.piece_count();
+        Self {
             torrent_file,
             stream,
-        })
+            capabilities,
+            peer_id,
+            state: PeerState::default(),
+            bitfield: Bitfield::new(piece_count),
+            pipeline: *PipelineConfig::get(),
+            queue_depth: PipelineConfig::get().min_requests,
+            peer_requests: vec![],
+        }
+    }
+
+    /// The extensions both we and the peer support, the only ones we may use with it.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// The id the peer sent in its handshake, see [`PeerId::client`] for what it runs.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    pub fn state(&self) -> PeerState {
+        self.state
+    }
+
+    /// The peer's bitfield, as far as it told us about its pieces so far. Lasts for the whole
+    /// connection, so nothing needs to be waited for before the next piece.
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// How many block requests are outstanding at once at most, adapted to the peer's speed
+    /// after every piece.
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth
+    }
+
+    /// Use another pipeline configuration than the process's for this peer.
+    pub fn set_pipeline(&mut self, pipeline: PipelineConfig) {
+        self.pipeline = pipeline;
+        self.queue_depth = pipeline.min_requests;
+    }
+
+    /// Wait until the peer tells us what it has: a bitfield, a have, or an unchoke from a peer
+    /// that sends neither. A peer staying silent about it for the request timeout isn't worth
+    /// handing pieces to.
+    pub fn wait_for_availability(&mut self) -> Result<()> {
+        let deadline = Instant::now() + self.pipeline.request_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                bail!(
+                    "peer didn't tell what it has within {:?}",
+                    self.pipeline.request_timeout
+                );
+            }
+            self.stream
+                .set_read_timeout(Some(remaining))
+                .context("set read timeout")?;
+            match self.receive() {
+                Result::Ok(
+                    PeerMessage::Bitfield(_) | PeerMessage::Have(_) | PeerMessage::Unchoke,
+                ) => break,
+                Result::Ok(_) => {}
+                Err(_) if Instant::now() >= deadline => {}
+                Err(e) => return Err(e.context("wait bitfield")),
+            }
+        }
+        self.stream
+            .set_read_timeout(None)
+            .context("clear read timeout")?;
+        Ok(())
     }
 
+    /// Download a piece, keeping up to [`Peer::queue_depth`] block requests outstanding whenever
+    /// the peer lets us. Blocks may arrive in any order, and messages about anything else at any
+    /// time in between. Can be called again for the next piece on the same connection, also after
+    /// a piece failed its hash check.
     pub fn download_a_piece(&mut self, piece_index: u32) -> Result<Vec<u8>> {
-        // Exchange multiple peer messages to download the file
-        self.wait_message(MessageTag::Bitfield)
-            .context("wait bitfield message")?;
-        let interested_message = Message {
-            tag: MessageTag::Interested,
-            payload: Vec::new(),
-        };
-        self.send_message(interested_message)
-            .context("send interested message")?;
-        self.wait_message(MessageTag::Unchoke)
-            .context("wait unchoke message")?;
-
-        let mut piece_length = self.torrent_file.info.piece_length as u32;
-        // Last piece might be smaller than other piece
-        if (piece_index + 1) as u64 * piece_length as u64 > self.torrent_file.info.length {
-            piece_length = (self.torrent_file.info.length % piece_length as u64) as u32
+        if !self.state.am_interested {
+            self.send_message(PeerMessage::Interested)
+                .context("send interested message")?;
         }
-        let mut all_blocks: Vec<u8> = Vec::with_capacity(piece_length as usize);
-        let block_size = 1 << 14;
-        let mut block_idx = 0;
-        let mut remaining = piece_length;
-        while remaining > 0 {
-            // Calculate begin and length
-            let begin = block_idx * block_size;
-            let length: u32;
-            if remaining > block_size {
-                length = block_size;
-                remaining -= block_size;
+
+        let piece_length = self.torrent_file.info.piece_size(piece_index as usize) as u32;
+        let mut all_blocks: Vec<u8> = vec![0; piece_length as usize];
+        let mut received = vec![false; piece_length.div_ceil(BLOCK_SIZE) as usize];
+        let mut missing = received.len();
+        // When each request was sent by the begin of its block, a choke makes the peer forget them
+        let mut outstanding: HashMap<u32, Instant> = HashMap::new();
+        let mut started: Option<Instant> = None;
+        // A peer keeping us choked is waited for as long as a request, the piece is left to
+        // another peer after that
+        let mut choked_since: Option<Instant> = None;
+        while missing > 0 {
+            // Peers send their bitfield before unchoking us, asking for more would be pointless
+            if !self.state.peer_choking && !self.bitfield.has(piece_index as usize) {
+                return Err(PeerError::PieceUnavailable(piece_index).into());
+            }
+            while outstanding.len() < self.queue_depth && !self.state.peer_choking {
+                let Some(block_idx) = (0..received.len())
+                    .find(|&i| !received[i] && !outstanding.contains_key(&(i as u32 * BLOCK_SIZE)))
+                else {
+                    break;
+                };
+                let begin = block_idx as u32 * BLOCK_SIZE;
+                self.send_message(PeerMessage::Request {
+                    index: piece_index,
+                    begin,
+                    length: BLOCK_SIZE.min(piece_length - begin),
+                })
+                .with_context(|| format!("send #{} request", block_idx))?;
+                outstanding.insert(begin, Instant::now());
+                started.get_or_insert_with(Instant::now);
+            }
+
+            if self.state.peer_choking {
+                choked_since.get_or_insert_with(Instant::now);
             } else {
-                length = remaining;
-                remaining = 0;
+                choked_since = None;
             }
 
-            // Prepare message
-            let mut request = Request::new(piece_index, begin, length);
-            let request_bytes = Vec::from(request.as_bytes_mut());
-            let message = Message {
-                tag: MessageTag::Request,
-                payload: request_bytes,
+            // Wait at most until the oldest request, or the choke, times out
+            let deadline = outstanding
+                .values()
+                .min()
+                .or(choked_since.as_ref())
+                .map(|since| *since + self.pipeline.request_timeout);
+            let arrived = self
+                .receive_within(
+                    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())),
+                )
+                .context("wait piece messages")?;
+            let Some(message) = arrived else {
+                if deadline.is_none_or(|deadline| Instant::now() < deadline) {
+                    continue;
+                }
+                if outstanding.is_empty() {
+                    return Err(PeerError::PieceUnavailable(piece_index).into());
+                }
+                // Tell the peer it can forget about the piece, another one gets it
+                for &begin in outstanding.keys() {
+                    let _ = self.send_message(PeerMessage::Cancel {
+                        index: piece_index,
+                        begin,
+                        length: BLOCK_SIZE.min(piece_length - begin),
+                    });
+                }
+                self.queue_depth = self.pipeline.min_requests;
+                bail!(
+                    "peer didn't answer {} requests for #{} piece within {:?}",
+                    outstanding.len(),
+                    piece_index,
+                    self.pipeline.request_timeout
+                );
             };
 
-            // Collect a single block
-            self.send_message(message)
-                .with_context(|| format!("send #{} request", block_idx))?;
-            let res = self
-                .wait_message(MessageTag::Piece)
-                .with_context(|| format!("wait #{} piece", block_idx))?;
-            let piece = Piece::ref_from_bytes(&res.payload[..])
-                .expect("always get all Piece response fields from peer");
-            assert_eq!(piece.index() as u32, piece_index);
-            assert_eq!(piece.begin() as u32, begin);
-            assert_eq!(piece.block().len() as u32, length);
-            all_blocks.extend(piece.block());
-
-            block_idx += 1;
+            match message {
+                PeerMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                } if index == piece_index => {
+                    let block_idx = (begin / BLOCK_SIZE) as usize;
+                    // Late answers to requests we asked again for are dropped
+                    if begin % BLOCK_SIZE != 0 || block_idx >= received.len() || received[block_idx]
+                    {
+                        continue;
+                    }
+                    let length = BLOCK_SIZE.min(piece_length - begin);
+                    if block.len() as u32 != length {
+                        bail!(
+                            "asked for {} bytes at {} of #{} piece, got {}",
+                            length,
+                            begin,
+                            piece_index,
+                            block.len()
+                        );
+                    }
+                    all_blocks[begin as usize..(begin + length) as usize].copy_from_slice(&block);
+                    received[block_idx] = true;
+                    missing -= 1;
+                    outstanding.remove(&begin);
+                }
+                PeerMessage::Choke => outstanding.clear(),
+                // Anything else only updates the state, or answers a request we gave up on
+                _ => {}
+            }
+        }
+        self.stream
+            .set_read_timeout(None)
+            .context("clear read timeout")?;
+
+        // Ask for as many blocks as the peer sends in a while, choked time counts as slow
+        if let Some(started) = started {
+            let rate = piece_length as f64 / started.elapsed().as_secs_f64().max(0.001);
+            let depth = (rate * QUEUE_TIME.as_secs_f64() / BLOCK_SIZE as f64) as usize;
+            self.queue_depth = depth.clamp(self.pipeline.min_requests, self.pipeline.max_requests);
+        }
+
+        let hash: [u8; 20] = Sha1::digest(&all_blocks).into();
+        if self.torrent_file.info.piece_hash(piece_index as usize) != Some(&hash[..]) {
+            return Err(PeerError::HashMismatch(piece_index).into());
         }
-        assert_eq!(all_blocks.len() as u32, piece_length);
 
         Ok(all_blocks)
     }
 
-    pub fn send_message(&mut self, message: Message) -> Result<()> {
-        let mut buf = BytesMut::with_capacity(4 /* length */ + 1 /* tag */ + message.payload.len());
-        buf.put_u32(1 + message.payload.len() as u32);
-        buf.put_u8(message.tag as u8);
-        buf.put(&message.payload[..]);
-        self.stream.write(&buf).context("write message to stream")?;
+    /// The blocks the peer asked for since the last call, as (index, begin, length), without
+    /// those it cancelled. Requests arrive whatever we're doing, so they're kept until we're
+    /// ready to answer.
+    pub fn take_requests(&mut self) -> Vec<(u32, u32, u32)> {
+        std::mem::take(&mut self.peer_requests)
+    }
 
+    /// Send a message, keeping track of our choking and interest.
+    pub fn send_message(&mut self, message: PeerMessage) -> Result<()> {
+        message
+            .write_to(&mut self.stream)
+            .with_context(|| format!("write {} message to stream", message.name()))?;
+        match message {
+            PeerMessage::Choke => self.state.am_choking = true,
+            PeerMessage::Unchoke => self.state.am_choking = false,
+            PeerMessage::Interested => self.state.am_interested = true,
+            PeerMessage::NotInterested => self.state.am_interested = false,
+            _ => {}
+        }
         Ok(())
     }
 
-    pub fn wait_message(&mut self, message_tag: MessageTag) -> Result<Message> {
-        // Read the message length prefix
-        let mut length_bytes = [0; 4];
-        self.stream
-            .read_exact(&mut length_bytes)
-            .context("read message length prefix from stream")?;
-        let length = u32::from_be_bytes(length_bytes) as usize;
-
-        // Read the message id
-        let mut id_bytes = [0; 1];
+    /// Receive the next message if it starts arriving within `timeout`, or whenever it does
+    /// without one. None when nothing arrived in time, which leaves the connection usable. Any
+    /// error means it isn't: a message cut off halfway leaves us out of step with the stream.
+    pub fn receive_within(&mut self, timeout: Option<Duration>) -> Result<Option<PeerMessage>> {
         self.stream
-            .read_exact(&mut id_bytes)
-            .context("read message id from stream")?;
-        let tag: MessageTag = id_bytes[0].into();
-        assert_eq!(tag, message_tag);
-
-        if length == 1 {
-            return Ok(Message {
-                tag,
-                payload: Vec::new(),
-            });
+            .set_read_timeout(timeout.map(|timeout| timeout.max(Duration::from_millis(1))))
+            .context("set read timeout")?;
+        match self.stream.peek(&mut [0; 1]) {
+            Result::Ok(0) => bail!("peer closed the connection"),
+            Result::Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e).context("wait for a message"),
         }
-
-        // Read the payload
-        let mut payload_bytes = Vec::with_capacity(length - 1);
-        // have to resize the vec or its len() would be 0
-        payload_bytes.resize_with(length - 1, || 0);
+        // Once a message started arriving it gets a whole request timeout to arrive
         self.stream
-            .read_exact(&mut payload_bytes)
-            .context("read message payload from stream")?;
+            .set_read_timeout(Some(self.pipeline.request_timeout))
+            .context("set read timeout")?;
+        self.receive().map(Some)
+    }
 
-        Ok(Message {
-            tag,
-            payload: payload_bytes.to_vec(),
-        })
+    /// Read the next message, whatever it is, and update the peer's choking and interest.
+    pub fn receive(&mut self) -> Result<PeerMessage> {
+        let message = PeerMessage::read_from(&mut self.stream).context("read message")?;
+        match message {
+            PeerMessage::Choke => self.state.peer_choking = true,
+            PeerMessage::Unchoke => self.state.peer_choking = false,
+            PeerMessage::Interested => self.state.peer_interested = true,
+            PeerMessage::NotInterested => self.state.peer_interested = false,
+            PeerMessage::Bitfield(ref payload) => {
+                self.bitfield = Bitfield::from_payload(payload, self.bitfield.piece_count())
+                    .context("peer sent an invalid bitfield")?;
+            }
+            PeerMessage::Have(piece_index) => self
+                .bitfield
+                .set(piece_index as usize)
+                .context("peer sent an invalid have")?,
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } if self.peer_requests.len() < MAX_PEER_REQUESTS => {
+                self.peer_requests.push((index, begin, length))
+            }
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => self
+                .peer_requests
+                .retain(|request| *request != (index, begin, length)),
+            _ => {}
+        }
+        Ok(message)
     }
 }
@@ -0,0 +1,288 @@
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::random::random_u64;
+
+// Azureus style: a dash, two letters naming the client, four digits of version, and a dash
+const CLIENT_PREFIX: &[u8; 8] = b"-BS0001-";
+const RANDOM_CHARS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+static SESSION_PEER_ID: OnceLock<PeerId> = OnceLock::new();
+
+/// The 20 bytes identifying a client to trackers and peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    /// A fresh id made of our client prefix and random characters.
+    pub fn generate() -> Self {
+        Self::with_prefix(CLIENT_PREFIX)
+    }
+
+    /// Fill up `prefix` with random characters. Prefixes longer than an id are cut.
+    pub fn with_prefix(prefix: &[u8]) -> Self {
+        let mut bytes = [0; 20];
+        let prefix_length = prefix.len().min(bytes.len());
+        bytes[..prefix_length].copy_from_slice(&prefix[..prefix_length]);
+        for byte in &mut bytes[prefix_length..] {
+            *byte = RANDOM_CHARS[random_u64() as usize % RANDOM_CHARS.len()];
+        }
+        Self(bytes)
+    }
+
+    /// The id used for every announce and handshake of this process, generated on first use
+    /// unless it was set with [`PeerId::set_session`] before.
+    pub fn session() -> Self {
+        *SESSION_PEER_ID.get_or_init(Self::generate)
+    }
+
+    /// Pick the session id, which has to happen before anything used it.
+    pub fn set_session(peer_id: Self) -> Result<()> {
+        if SESSION_PEER_ID.set(peer_id).is_err() {
+            bail!("the session peer id is already in use");
+        }
+        Ok(())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Tell the client software from the id, for the Azureus, Shadow and Mainline conventions
+    /// and a few clients doing their own thing. Unknown Azureus codes are named by the code.
+    pub fn client(&self) -> Option<Client> {
+        special_client(&self.0)
+            .or_else(|| azureus_client(&self.0))
+            .or_else(|| mainline_client(&self.0))
+            .or_else(|| shadow_client(&self.0))
+    }
+}
+
+/// The client software behind a peer id.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Client {
+    pub name: String,
+    /// Dotted version, empty when the id doesn't tell
+    pub version: String,
+}
+
+impl Client {
+    fn new(name: &str, version: String) -> Self {
+        Self {
+            name: name.to_string(),
+            version,
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
+    }
+}
+
+// Clients whose ids follow none of the conventions, or would be mistaken for another one
+fn special_client(id: &[u8; 20]) -> Option<Client> {
+    if id.starts_with(b"exbc") {
+        let name = if &id[6..10] == b"LORD" {
+            "BitLord"
+        } else {
+            "BitComet"
+        };
+        return Some(Client::new(name, format!("{}.{:02}", id[4], id[5])));
+    }
+    if id.starts_with(b"AZ2500BT") {
+        return Some(Client::new("BitTyrant", String::new()));
+    }
+    if let Some(version) = id.strip_prefix(b"-ML") {
+        let version = version.split(|byte| *byte == b'-').next()?;
+        return Some(Client::new(
+            "MLDonkey",
+            String::from_utf8_lossy(version).into_owned(),
+        ));
+    }
+    if let Some(version) = id.strip_prefix(b"-BOW") {
+        return Some(Client::new(
+            "Bits on Wheels",
+            String::from_utf8_lossy(&version[..3]).into_owned(),
+        ));
+    }
+    if let Some(version) = id.strip_prefix(b"XBT") {
+        return version[..3]
+            .iter()
+            .all(u8::is_ascii_digit)
+            .then(|| Client::new("XBT Client", dotted(&version[..3])));
+    }
+    if let Some(build) = id.strip_prefix(b"OP") {
+        return build[..4]
+            .iter()
+            .all(u8::is_ascii_digit)
+            .then(|| Client::new("Opera", String::from_utf8_lossy(&build[..4]).into_owned()));
+    }
+    None
+}
+
+// -qB4630-: two letters naming the client and four version characters between dashes
+fn azureus_client(id: &[u8; 20]) -> Option<Client> {
+    if id[0] != b'-' || id[7] != b'-' || !id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let code = String::from_utf8_lossy(&id[1..3]);
+    let name = azureus_client_name(&code).unwrap_or(&code);
+    Some(Client::new(name, dotted(&id[3..7])))
+}
+
+fn azureus_client_name(code: &str) -> Option<&'static str> {
+    let name = match code {
+        "AG" | "A~" => "Ares",
+        "AR" => "Arctic",
+        "AT" => "Artemis",
+        "AX" => "BitPump",
+        "AZ" => "Vuze",
+        "BB" => "BitBuddy",
+        "BC" => "BitComet",
+        "BF" => "Bitflu",
+        "BI" => "BiglyBT",
+        "BR" => "BitRocket",
+        "BT" => "BitTorrent",
+        "BW" => "BitWombat",
+        "CD" => "Enhanced CTorrent",
+        "DE" => "Deluge",
+        "EB" => "EBit",
+        "FD" => "Free Download Manager",
+        "FG" => "FlashGet",
+        "FW" => "FrostWire",
+        "HL" => "Halite",
+        "KG" => "KGet",
+        "KT" => "KTorrent",
+        "LT" => "libTorrent",
+        "lt" => "libtorrent",
+        "LW" => "LimeWire",
+        "PI" => "PicoTorrent",
+        "qB" => "qBittorrent",
+        "RT" => "Retriever",
+        "SD" => "Thunder",
+        "ST" => "SymTorrent",
+        "TL" => "Tribler",
+        "TR" => "Transmission",
+        "TT" => "TuoTu",
+        "TX" => "Tixati",
+        "UM" => "µTorrent Mac",
+        "UT" => "µTorrent",
+        "UW" => "µTorrent Web",
+        "WD" => "WebTorrent Desktop",
+        "WW" => "WebTorrent",
+        "XL" => "Xunlei",
+        "ZT" => "ZipTorrent",
+        _ => return None,
+    };
+    Some(name)
+}
+
+// M4-3-6--: a letter followed by three numbers separated by dashes
+fn mainline_client(id: &[u8; 20]) -> Option<Client> {
+    let name = match id[0] {
+        b'M' => "BitTorrent",
+        b'Q' => "Queen Bee",
+        _ => return None,
+    };
+    let parts: Vec<&[u8]> = id[1..8].split(|byte| *byte == b'-').take(3).collect();
+    if parts.len() < 3
+        || !parts
+            .iter()
+            .all(|part| !part.is_empty() && part.iter().all(u8::is_ascii_digit))
+    {
+        return None;
+    }
+    let version: Vec<String> = parts
+        .iter()
+        .map(|part| String::from_utf8_lossy(part).into_owned())
+        .collect();
+    Some(Client::new(name, version.join(".")))
+}
+
+// S58B-----: a letter naming the client, up to five version characters padded with dashes, and
+// three more dashes
+fn shadow_client(id: &[u8; 20]) -> Option<Client> {
+    let name = match id[0] {
+        b'A' => "ABC",
+        b'O' => "Osprey Permaseed",
+        b'Q' => "BTQueue",
+        b'R' => "Tribler",
+        b'S' => "Shadow",
+        b'T' => "BitTornado",
+        b'U' => "UPnP NAT Bit Torrent",
+        _ => return None,
+    };
+    if &id[6..9] != b"---" {
+        return None;
+    }
+    let version_length = id[1..6].iter().position(|byte| *byte == b'-').unwrap_or(5);
+    let (version, padding) = id[1..6].split_at(version_length);
+    if version.is_empty()
+        || !version
+            .iter()
+            .all(|byte| byte.is_ascii_alphanumeric() || *byte == b'.')
+        || !padding.iter().all(|byte| *byte == b'-')
+    {
+        return None;
+    }
+    Some(Client::new(name, dotted(version)))
+}
+
+// Every character is a version number, digits count from 0, letters go on from 10 so that e.g.
+// "T03I" is 0.3.18. Trailing zeros after the minor version are dropped.
+fn dotted(version: &[u8]) -> String {
+    let mut numbers: Vec<u32> = version
+        .iter()
+        .map(|byte| match byte {
+            b'0'..=b'9' => (byte - b'0') as u32,
+            b'A'..=b'Z' => (byte - b'A') as u32 + 10,
+            b'a'..=b'z' => (byte - b'a') as u32 + 36,
+            _ => 62,
+        })
+        .collect();
+    while numbers.len() > 2 && numbers.last() == Some(&0) {
+        numbers.pop();
+    }
+    numbers
+        .iter()
+        .map(|number| number.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+impl From<[u8; 20]> for PeerId {
+    fn from(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+}
+
+/// Parses a whole 20 character id, or a shorter prefix to be filled up with random characters.
+impl FromStr for PeerId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() > 20 {
+            bail!("peer id should be at most 20 bytes, got {}", s.len());
+        }
+        Ok(Self::with_prefix(s.as_bytes()))
+    }
+}
+
+/// Shown as text when it's printable, which is the case for most clients, in hex otherwise.
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.iter().all(|byte| byte.is_ascii_graphic()) {
+            write!(f, "{}", String::from_utf8_lossy(&self.0))
+        } else {
+            write!(f, "{}", hex::encode(self.0))
+        }
+    }
+}
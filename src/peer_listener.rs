@@ -0,0 +1,136 @@
+use crate::handshake::Handshake;
+use crate::peer::Peer;
+use crate::peer_id::PeerId;
+use crate::torrent_file::TorrentFile;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Peers that connect but don't send a handshake are dropped after this long
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Routes = Arc<Mutex<HashMap<[u8; 20], (TorrentFile, Sender<(SocketAddr, Peer)>)>>>;
+
+/// Accepts connections from peers and hands each to the torrent it asks for in its handshake.
+/// The accepting thread lives as long as the process.
+pub struct PeerListener {
+    local_addr: SocketAddr,
+    routes: Routes,
+}
+
+impl PeerListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr).context("bind peer listener")?;
+        Self::serve(vec![listener])
+    }
+
+    /// Listen on `port` of every local address, IPv6 ones too unless the system has no IPv6.
+    pub fn bind_any(port: u16) -> Result<Self> {
+        let ipv6 = TcpListener::bind((Ipv6Addr::UNSPECIFIED, port));
+        let port = match &ipv6 {
+            Ok(listener) => listener
+                .local_addr()
+                .context("get listener address")?
+                .port(),
+            Err(_) => port,
+        };
+        // Taken already when the IPv6 socket accepts IPv4 connections as well, as on Linux
+        let ipv4 = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port));
+        let listeners = match (ipv6, ipv4) {
+            (Err(e), Err(_)) => return Err(e).context("bind peer listener"),
+            (ipv6, ipv4) => [ipv6, ipv4].into_iter().flatten().collect(),
+        };
+        Self::serve(listeners)
+    }
+
+    fn serve(listeners: Vec<TcpListener>) -> Result<Self> {
+        let local_addr = listeners[0].local_addr().context("get listener address")?;
+        let routes: Routes = Default::default();
+
+        for listener in listeners {
+            let thread_routes = routes.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Some(stream) = stream.ok() else {
+                        continue;
+                    };
+                    let routes = thread_routes.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = accept(stream, &routes) {
+                            eprintln!("rejected inbound peer: {:#}", e);
+                        }
+                    });
+                }
+            });
+        }
+
+        Ok(Self { local_addr, routes })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The port to announce to trackers.
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    /// Start accepting peers for a torrent, which arrive handshaken on the returned receiver.
+    pub fn register(&self, torrent_file: &TorrentFile) -> Result<Receiver<(SocketAddr, Peer)>> {
+        let info_hash = torrent_file.info.hash_info().context("hash info")?;
+        let (tx, rx) = mpsc::channel();
+        let mut routes = self.routes.lock().unwrap();
+        if routes.contains_key(&info_hash) {
+            bail!("{} is already registered", hex::encode(info_hash));
+        }
+        routes.insert(info_hash, (torrent_file.clone(), tx));
+        Ok(rx)
+    }
+
+    /// Stop accepting peers for a torrent, peers asking for it are turned away again.
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.routes.lock().unwrap().remove(info_hash);
+    }
+}
+
+// The connecting peer speaks first, and only gets our handshake for a torrent we serve
+fn accept(mut stream: TcpStream, routes: &Routes) -> Result<()> {
+    let peer_addr = stream.peer_addr().context("get peer address")?;
+    // IPv4 peers reaching an IPv6 socket have a mapped address, which isn't the one they're
+    // known by
+    let peer_addr = SocketAddr::new(peer_addr.ip().to_canonical(), peer_addr.port());
+    stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .context("set read timeout")?;
+    let theirs = Handshake::read_from(&mut stream)
+        .with_context(|| format!("read handshake of {}", peer_addr))?;
+
+    let Some((torrent_file, tx)) = routes.lock().unwrap().get(&theirs.info_hash).cloned() else {
+        bail!(
+            "{} asked for unknown info hash {}",
+            peer_addr,
+            hex::encode(theirs.info_hash)
+        );
+    };
+    let ours = Handshake::new(theirs.info_hash, PeerId::session());
+    ours.write_to(&mut stream)
+        .with_context(|| format!("send handshake to {}", peer_addr))?;
+    stream
+        .set_read_timeout(None)
+        .context("clear read timeout")?;
+
+    let peer = Peer::from_stream(
+        stream,
+        torrent_file,
+        ours.capabilities().negotiate(theirs.capabilities()),
+        theirs.peer_id.into(),
+    );
+    // The torrent may have been unregistered in the meantime, the peer is dropped then
+    let _ = tx.send((peer_addr, peer));
+    Ok(())
+}
@@ -0,0 +1,255 @@
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use std::io::{Read, Write};
+
+/// Longest message we accept from a peer, length prefix excluded. Fits the bitfield of a torrent
+/// with 8 million pieces, while a peer announcing more can't make us allocate gigabytes.
+pub const MAX_MESSAGE_LENGTH: u32 = 1 << 20;
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+const EXTENDED: u8 = 20;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum MessageError {
+    #[error("message of {0} bytes is longer than the maximum of {MAX_MESSAGE_LENGTH}")]
+    TooLong(u32),
+    #[error("message is truncated, got {got} of {expected} bytes")]
+    Truncated { expected: usize, got: usize },
+    #[error("message {id} can't be {length} bytes long")]
+    InvalidLength { id: u8, length: u32 },
+}
+
+/// A message of the peer wire protocol (BEP 3), with the port (BEP 5) and extended (BEP 10)
+/// messages. Messages we don't know are kept as they are, so that they can be skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl PeerMessage {
+    /// The message id, keep-alives have none.
+    pub fn id(&self) -> Option<u8> {
+        let id = match self {
+            PeerMessage::KeepAlive => return None,
+            PeerMessage::Choke => CHOKE,
+            PeerMessage::Unchoke => UNCHOKE,
+            PeerMessage::Interested => INTERESTED,
+            PeerMessage::NotInterested => NOT_INTERESTED,
+            PeerMessage::Have(_) => HAVE,
+            PeerMessage::Bitfield(_) => BITFIELD,
+            PeerMessage::Request { .. } => REQUEST,
+            PeerMessage::Piece { .. } => PIECE,
+            PeerMessage::Cancel { .. } => CANCEL,
+            PeerMessage::Port(_) => PORT,
+            PeerMessage::Extended { .. } => EXTENDED,
+            PeerMessage::Unknown { id, .. } => *id,
+        };
+        Some(id)
+    }
+
+    /// A short name for logs and errors, without the payload.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PeerMessage::KeepAlive => "keep-alive",
+            PeerMessage::Choke => "choke",
+            PeerMessage::Unchoke => "unchoke",
+            PeerMessage::Interested => "interested",
+            PeerMessage::NotInterested => "not interested",
+            PeerMessage::Have(_) => "have",
+            PeerMessage::Bitfield(_) => "bitfield",
+            PeerMessage::Request { .. } => "request",
+            PeerMessage::Piece { .. } => "piece",
+            PeerMessage::Cancel { .. } => "cancel",
+            PeerMessage::Port(_) => "port",
+            PeerMessage::Extended { .. } => "extended",
+            PeerMessage::Unknown { .. } => "unknown",
+        }
+    }
+
+    /// The whole message, length prefix included.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = BytesMut::new();
+        match self {
+            PeerMessage::KeepAlive
+            | PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested => {}
+            PeerMessage::Have(index) => payload.put_u32(*index),
+            PeerMessage::Bitfield(bitfield) => payload.put(&bitfield[..]),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                payload.put_u32(*index);
+                payload.put_u32(*begin);
+                payload.put_u32(*length);
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                payload.put_u32(*index);
+                payload.put_u32(*begin);
+                payload.put(&block[..]);
+            }
+            PeerMessage::Port(port) => payload.put_u16(*port),
+            PeerMessage::Extended { id, payload: data } => {
+                payload.put_u8(*id);
+                payload.put(&data[..]);
+            }
+            PeerMessage::Unknown { payload: data, .. } => payload.put(&data[..]),
+        }
+
+        let id = self.id();
+        let length = id.map_or(0, |_| 1) + payload.len();
+        let mut bytes = BytesMut::with_capacity(4 + length);
+        bytes.put_u32(length as u32);
+        if let Some(id) = id {
+            bytes.put_u8(id);
+        }
+        bytes.put(payload);
+        bytes.to_vec()
+    }
+
+    /// Parse a whole message, length prefix included. Bytes after the message are ignored.
+    pub fn parse(bytes: &[u8]) -> Result<Self, MessageError> {
+        if bytes.len() < 4 {
+            return Err(MessageError::Truncated {
+                expected: 4,
+                got: bytes.len(),
+            });
+        }
+        let length = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(MessageError::TooLong(length));
+        }
+        let expected = 4 + length as usize;
+        if bytes.len() < expected {
+            return Err(MessageError::Truncated {
+                expected,
+                got: bytes.len(),
+            });
+        }
+        Self::parse_body(&bytes[4..expected])
+    }
+
+    // The message after the length prefix, which is already checked to be complete
+    fn parse_body(body: &[u8]) -> Result<Self, MessageError> {
+        let Some((&id, mut payload)) = body.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+        let invalid_length = MessageError::InvalidLength {
+            id,
+            length: body.len() as u32,
+        };
+        let message = match id {
+            CHOKE | UNCHOKE | INTERESTED | NOT_INTERESTED if !payload.is_empty() => {
+                return Err(invalid_length)
+            }
+            CHOKE => PeerMessage::Choke,
+            UNCHOKE => PeerMessage::Unchoke,
+            INTERESTED => PeerMessage::Interested,
+            NOT_INTERESTED => PeerMessage::NotInterested,
+            HAVE if payload.len() == 4 => PeerMessage::Have(payload.get_u32()),
+            BITFIELD => PeerMessage::Bitfield(payload.to_vec()),
+            REQUEST | CANCEL if payload.len() == 12 => {
+                let (index, begin, length) =
+                    (payload.get_u32(), payload.get_u32(), payload.get_u32());
+                if id == REQUEST {
+                    PeerMessage::Request {
+                        index,
+                        begin,
+                        length,
+                    }
+                } else {
+                    PeerMessage::Cancel {
+                        index,
+                        begin,
+                        length,
+                    }
+                }
+            }
+            PIECE if payload.len() >= 8 => PeerMessage::Piece {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                block: payload.to_vec(),
+            },
+            PORT if payload.len() == 2 => PeerMessage::Port(payload.get_u16()),
+            EXTENDED if !payload.is_empty() => PeerMessage::Extended {
+                id: payload.get_u8(),
+                payload: payload.to_vec(),
+            },
+            HAVE | REQUEST | CANCEL | PIECE | PORT | EXTENDED => return Err(invalid_length),
+            _ => PeerMessage::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
+        };
+        Ok(message)
+    }
+
+    pub fn write_to(&self, stream: &mut impl Write) -> Result<()> {
+        stream.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Read the next message. The length is checked before anything is allocated for it.
+    pub fn read_from(stream: &mut impl Read) -> Result<Self> {
+        let mut length_bytes = [0; 4];
+        stream.read_exact(&mut length_bytes)?;
+        let length = u32::from_be_bytes(length_bytes);
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(MessageError::TooLong(length).into());
+        }
+        let mut body = vec![0; length as usize];
+        stream.read_exact(&mut body)?;
+        Ok(Self::parse_body(&body)?)
+    }
+}
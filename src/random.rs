@@ -0,0 +1,11 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// A random number for ids and transaction ids, not for anything that has to stay secret.
+///
+/// std seeds the keys of `RandomState` from the OS once per thread, then bumps them for every
+/// new `RandomState`. SipHash mixes those keys well, so hashing through a fresh one gives a
+/// different, unpredictable looking value on every call without pulling in a crate.
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
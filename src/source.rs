@@ -0,0 +1,128 @@
+use crate::http_client::blocking_client;
+use crate::magnet::MagnetLink;
+use crate::torrent_file::{parse_torrent_file, TorrentFile};
+use anyhow::{bail, Context, Ok, Result};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const TORRENT_EXTENSION: &str = "torrent";
+
+/// Where the subcommands get their torrents from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentSource {
+    /// `-`, a single .torrent piped into stdin
+    Stdin,
+    /// An `http://` or `https://` URL serving a .torrent
+    Url(String),
+    /// A magnet link, whose metadata is fetched from peers
+    Magnet(MagnetLink),
+    /// A directory whose .torrent files are processed as a batch
+    Directory(PathBuf),
+    /// A single .torrent on disk
+    File(PathBuf),
+}
+
+impl FromStr for TorrentSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(if s == "-" {
+            TorrentSource::Stdin
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            TorrentSource::Url(s.to_string())
+        } else if s.starts_with("magnet:") {
+            TorrentSource::Magnet(MagnetLink::parse(s).context("parse magnet link")?)
+        } else if Path::new(s).is_dir() {
+            TorrentSource::Directory(PathBuf::from(s))
+        } else {
+            TorrentSource::File(PathBuf::from(s))
+        })
+    }
+}
+
+impl fmt::Display for TorrentSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TorrentSource::Stdin => write!(f, "stdin"),
+            TorrentSource::Url(url) => write!(f, "{}", url),
+            TorrentSource::Magnet(magnet) => {
+                write!(f, "magnet link {}", hex::encode(magnet.info_hash))
+            }
+            TorrentSource::Directory(path) | TorrentSource::File(path) => {
+                write!(f, "{}", path.display())
+            }
+        }
+    }
+}
+
+impl TorrentSource {
+    pub fn is_batch(&self) -> bool {
+        matches!(self, TorrentSource::Directory(_))
+    }
+
+    pub fn load(&self) -> Result<Vec<TorrentFile>> {
+        Ok(match self {
+            TorrentSource::Stdin => {
+                let mut contents = vec![];
+                io::stdin()
+                    .read_to_end(&mut contents)
+                    .context("read stdin")?;
+                vec![parse_torrent_file(&contents[..]).context("parse file")?]
+            }
+            TorrentSource::Url(url) => {
+                let contents = blocking_client()?
+                    .get(url)
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .context("request the url")?
+                    .bytes()
+                    .context("read response as bytes")?;
+                vec![parse_torrent_file(&contents[..]).context("parse file")?]
+            }
+            TorrentSource::Magnet(magnet) => {
+                vec![magnet.fetch_torrent_file().context("fetch metadata")?]
+            }
+            TorrentSource::Directory(dir) => {
+                let paths = list_torrent_files(dir).context("list torrent files")?;
+                if paths.is_empty() {
+                    bail!("no .torrent files in {}", dir.display());
+                }
+                paths
+                    .iter()
+                    .map(|path| {
+                        read_torrent_file(path).with_context(|| format!("load {}", path.display()))
+                    })
+                    .collect::<Result<_>>()?
+            }
+            TorrentSource::File(path) => vec![read_torrent_file(path)?],
+        })
+    }
+
+    /// Load the source, refusing batches for the subcommands that work on exactly one torrent.
+    pub fn load_one(&self) -> Result<TorrentFile> {
+        if self.is_batch() {
+            bail!("{} is a directory, expected a single torrent", self);
+        }
+        self.load()?.pop().context("get the torrent")
+    }
+}
+
+pub fn list_torrent_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = vec![];
+    for entry in fs::read_dir(dir).context("read directory")? {
+        let path = entry.context("read directory entry")?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == TORRENT_EXTENSION) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn read_torrent_file(path: &Path) -> Result<TorrentFile> {
+    let contents = fs::read(path).context("open file")?;
+    parse_torrent_file(&contents[..]).context("parse file")
+}
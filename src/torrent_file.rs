@@ -1,15 +1,34 @@
-use anyhow::{Context, Ok, Result};
+use anyhow::{bail, Context, Ok, Result};
 use serde::Serialize;
 use sha1::{Digest, Sha1};
+use std::path::PathBuf;
 
 use crate::decoder::{decode, Decoded};
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct TorrentFile {
     pub announce: String,
+    /// Tiers of trackers from `announce-list` (BEP 12), empty when the torrent only has one
+    pub announce_list: Vec<Vec<String>>,
     pub info: TorrentFileInfo,
 }
 
+impl TorrentFile {
+    /// Every tracker to announce to, those of `announce-list` in order, or else `announce`.
+    pub fn trackers(&self) -> Vec<String> {
+        if self.announce_list.is_empty() {
+            return vec![self.announce.clone()];
+        }
+        let mut trackers: Vec<String> = vec![];
+        for tracker in self.announce_list.iter().flatten() {
+            if !trackers.contains(tracker) {
+                trackers.push(tracker.clone());
+            }
+        }
+        trackers
+    }
+}
+
 #[derive(Serialize, PartialEq, Debug, Clone)]
 pub struct TorrentFileInfo {
     pub name: String,
@@ -17,11 +36,23 @@ pub struct TorrentFileInfo {
     pub piece_length: u64,
     #[serde(with = "serde_bytes")]
     pub pieces: Vec<u8>,
+    /// Size of the whole data, the sum of the files' lengths for a multi-file torrent
     pub length: u64,
+    /// The files of a multi-file torrent, which then lives in a directory named after it. A
+    /// multi-file info hand-built without `info_hash` would be hashed with `length` included.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<TorrentFileInfoFile>>,
+    /// Hash of the info dictionary as it was read, which may hold keys the fields above don't
+    /// keep (e.g. `private` or `source`). Left out, the hash is computed from the fields.
+    #[serde(skip)]
+    pub info_hash: Option<[u8; 20]>,
 }
 
 impl TorrentFileInfo {
     pub fn hash_info(&self) -> Result<[u8; 20]> {
+        if let Some(info_hash) = self.info_hash {
+            return Ok(info_hash);
+        }
         let bencoded_info_dictionary =
             serde_bencode::to_bytes(&self).context("hash info dictionary")?;
         let mut hasher = Sha1::new();
@@ -34,71 +65,186 @@ impl TorrentFileInfo {
     }
 
     pub fn url_encoded_hash_info(&self) -> Result<String> {
-        Ok(self.hash_info().context("get hash info")?.iter().fold(
-            "".to_string(),
-            |mut acc, &byte| {
-                acc.push_str("%");
-                acc.push_str(&hex::encode([byte]));
-                acc
-            },
+        Ok(url_encode_bytes(
+            &self.hash_info().context("get hash info")?,
         ))
     }
 
     pub fn hex_pieces(&self) -> Result<Vec<String>> {
-        Ok(self
-            .pieces
-            .chunks(20)
-            .map(|chunk| hex::encode(chunk))
-            .collect())
+        Ok(self.pieces.chunks(20).map(hex::encode).collect())
     }
-}
 
-pub fn parse_torrent_file(contents: &[u8]) -> Result<TorrentFile> {
-    let decoded_value = decode(contents).context("decode file contents")?.1;
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len() / 20
+    }
 
-    let mut announce: Option<String> = None;
-    let mut length: Option<u64> = None;
-    let mut name: Option<String> = None;
-    let mut piece_length: Option<u64> = None;
-    let mut pieces: Option<Vec<u8>> = None;
-    if let Decoded::Dictionary(dict) = decoded_value {
-        if let Decoded::String(s) = dict.get("announce").context("should contain announce")? {
-            announce = Some(
-                std::str::from_utf8(s)
-                    .context("announce isn't in valid UTF-8 format")?
-                    .to_string(),
-            );
+    pub fn piece_hash(&self, piece_index: usize) -> Option<&[u8]> {
+        self.pieces.chunks_exact(20).nth(piece_index)
+    }
+
+    /// Size of the given piece, the last piece might be smaller than the others.
+    pub fn piece_size(&self, piece_index: usize) -> u64 {
+        let begin = piece_index as u64 * self.piece_length;
+        self.piece_length.min(self.length.saturating_sub(begin))
+    }
+
+    /// The files the torrent's data is split into, in order. A single-file torrent has exactly
+    /// one entry named after the torrent, the files of a multi-file one are under a directory
+    /// named after it.
+    pub fn files(&self) -> Vec<TorrentFileEntry> {
+        let Some(files) = &self.files else {
+            return vec![TorrentFileEntry {
+                path: PathBuf::from(&self.name),
+                offset: 0,
+                length: self.length,
+            }];
         };
-        if let Decoded::Dictionary(info) = dict.get("info").context("should contain info")? {
-            if let Decoded::Integer(n) = info.get("length").context("should contain length")? {
-                length = Some(n.to_owned() as u64);
-            }
-            if let Decoded::String(s) = info.get("name").context("should contain name")? {
-                name = Some(
-                    std::str::from_utf8(s)
-                        .context("name isn't in valid UTF-8 format")?
-                        .to_string(),
-                );
-            }
-            if let Decoded::Integer(n) = info
-                .get("piece length")
-                .context("should contain piece length")?
-            {
-                piece_length = Some(n.to_owned() as u64);
-            }
-            if let Decoded::String(s) = info.get("pieces").context("should contain pieces")? {
-                pieces = Some(s.to_vec());
-            }
-        }
+        let mut offset = 0;
+        files
+            .iter()
+            .map(|file| {
+                let entry = TorrentFileEntry {
+                    path: std::iter::once(&self.name).chain(&file.path).collect(),
+                    offset,
+                    length: file.length,
+                };
+                offset += file.length;
+                entry
+            })
+            .collect()
     }
+}
+
+/// A file of a multi-file torrent as listed in its info dictionary.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct TorrentFileInfoFile {
+    pub length: u64,
+    /// Directories then file name, relative to the torrent's directory
+    pub path: Vec<String>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct TorrentFileEntry {
+    pub path: PathBuf,
+    /// Where the file starts within the concatenated torrent data
+    pub offset: u64,
+    pub length: u64,
+}
+
+pub fn url_encode_bytes(bytes: &[u8]) -> String {
+    bytes.iter().fold("".to_string(), |mut acc, &byte| {
+        acc.push('%');
+        acc.push_str(&hex::encode([byte]));
+        acc
+    })
+}
+
+pub fn parse_torrent_file(contents: &[u8]) -> Result<TorrentFile> {
+    let decoded_value = decode(contents).context("decode file contents")?.1;
 
+    let announce_list = decoded_value
+        .get_list("announce-list")
+        .map(parse_announce_list)
+        .unwrap_or_default();
+    // Torrents with an announce-list may leave announce out
+    let announce = match decoded_value.get_str("announce") {
+        Some(announce) => announce.to_string(),
+        None => announce_list
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .context("should contain announce in valid UTF-8 format, or announce-list")?,
+    };
     Ok(TorrentFile {
-        announce: announce.context("get announce from torrent file")?,
-        info: TorrentFileInfo {
-            length: length.context("get info.length from torrent file")?,
-            name: name.context("get info.name from torrent file")?,
-            piece_length: piece_length.context("get info.piece_length")?,
-            pieces: pieces.context("get info.pieces")?,
-        },
+        announce,
+        announce_list,
+        info: parse_info(
+            decoded_value
+                .get_dict("info")
+                .context("should contain info")?,
+        )?,
+    })
+}
+
+/// Parse a bare bencoded info dictionary, e.g. the metadata fetched for a magnet link.
+pub fn parse_info_dictionary(contents: &[u8]) -> Result<TorrentFileInfo> {
+    let decoded_value = decode(contents).context("decode info dictionary")?.1;
+    parse_info(&decoded_value)
+}
+
+// Trackers that aren't strings in valid UTF-8 format are skipped, and so are tiers left empty
+fn parse_announce_list(tiers: &[Decoded]) -> Vec<Vec<String>> {
+    tiers
+        .iter()
+        .filter_map(|tier| tier.as_list())
+        .map(|tier| {
+            tier.iter()
+                .filter_map(|tracker| tracker.as_str())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .filter(|tier| !tier.is_empty())
+        .collect()
+}
+
+fn parse_info(info: &Decoded) -> Result<TorrentFileInfo> {
+    // Decoding keeps every key in order, so re-encoding gives back the bytes that were hashed
+    let info_hash = Sha1::digest(info.encode()).into();
+    let files = match info.get_list("files") {
+        Some(files) => Some(
+            files
+                .iter()
+                .enumerate()
+                .map(|(i, file)| parse_info_file(file).with_context(|| format!("#{} file", i)))
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        None => None,
+    };
+    let length = match &files {
+        Some(files) => files
+            .iter()
+            .try_fold(0u64, |total, file| total.checked_add(file.length))
+            .context("total length of the files should fit 64 bits")?,
+        None => info
+            .get_u64("length")
+            .context("should contain non-negative length, or files")?,
+    };
+    Ok(TorrentFileInfo {
+        length,
+        files,
+        name: info
+            .get_str("name")
+            .context("should contain name in valid UTF-8 format")?
+            .to_string(),
+        piece_length: info
+            .get_u64("piece length")
+            .context("should contain non-negative piece length")?,
+        pieces: info
+            .get_bytes("pieces")
+            .context("should contain pieces")?
+            .to_vec(),
+        info_hash: Some(info_hash),
+    })
+}
+
+fn parse_info_file(file: &Decoded) -> Result<TorrentFileInfoFile> {
+    let path = file
+        .get_list("path")
+        .context("should contain path")?
+        .iter()
+        .map(|part| {
+            part.as_str()
+                .map(str::to_string)
+                .context("path should be strings in valid UTF-8 format")
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if path.is_empty() {
+        bail!("path should not be empty");
+    }
+    Ok(TorrentFileInfoFile {
+        length: file
+            .get_u64("length")
+            .context("should contain non-negative length")?,
+        path,
     })
 }
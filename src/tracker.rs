@@ -1,103 +1,432 @@
-use anyhow::{Context, Ok, Result};
-use reqwest;
-use std::net::Ipv4Addr;
+use anyhow::{bail, Context, Ok, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
 
+use crate::async_tracker::{announce_blocking, track_blocking, SwarmResponse};
 use crate::decoder::{decode, Decoded};
-use crate::torrent_file::TorrentFile;
+use crate::http_client::blocking_client;
+use crate::peer_id::{Client, PeerId};
+use crate::random::random_u64;
+use crate::torrent_file::{url_encode_bytes, TorrentFile};
+use crate::udp_tracker::{UdpTracker, MAX_UDP_SCRAPE_INFO_HASHES, UDP_SCHEME};
+
+/// How long each tracker gets to answer when announcing to all trackers of a torrent at once
+pub const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
 
 #[derive(Debug, PartialEq)]
 pub struct TrackerResponse {
-    pub complete: i64,
-    pub min_interval: i64,
-    pub incomplete: i64,
+    pub complete: Option<i64>,
+    pub min_interval: Option<i64>,
+    pub incomplete: Option<i64>,
     pub interval: i64,
     pub peer_addr_list: Vec<PeerAddr>,
+    /// Peers the tracker named by hostname. Parsing doesn't look them up, announcing does and
+    /// moves them to `peer_addr_list`.
+    pub peer_hosts: Vec<PeerHost>,
+    /// Something the tracker wants us to know, the announce still succeeded
+    pub warning_message: Option<String>,
+    /// To be sent back on our next announces to the tracker
+    pub tracker_id: Option<Vec<u8>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    /// The tracker refused the announce or scrape, e.g. because the torrent isn't registered
+    #[error("tracker failure: {0}")]
+    Failure(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct PeerAddr {
-    pub ip: Ipv4Addr,
-    pub port: u16,
+    pub addr: SocketAddr,
+    /// Only known when the tracker sent a non-compact peer list
+    pub peer_id: Option<[u8; 20]>,
 }
 
 impl PeerAddr {
-    pub fn to_string(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            peer_id: None,
+        }
+    }
+
+    /// The client the peer runs, when the tracker told its id.
+    pub fn client(&self) -> Option<Client> {
+        self.peer_id.and_then(|peer_id| PeerId(peer_id).client())
+    }
+}
+
+/// A peer of a non-compact peer list whose ip is a hostname.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PeerHost {
+    pub host: String,
+    pub port: u16,
+    pub peer_id: Option<[u8; 20]>,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addr)
+    }
+}
+
+/// Swarm statistics of a single torrent, as returned by a scrape.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub struct ScrapeStats {
+    pub complete: i64,
+    pub downloaded: i64,
+    pub incomplete: i64,
+}
+
+/// What a client reports to the tracker on every announce.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceParams {
+    pub info_hash: [u8; 20],
+    pub peer_id: PeerId,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    /// How many peers we'd like, the tracker's default if unset
+    pub numwant: Option<u32>,
+    /// Lets the tracker recognize us when our IP address changes, [`announce_key`] if unset
+    pub key: Option<u32>,
+    /// Our address, when the tracker can't tell it from the connection
+    pub ip: Option<IpAddr>,
+    /// Ask for peer lists without peer ids
+    pub no_peer_id: bool,
+}
+
+impl AnnounceParams {
+    pub fn new(info_hash: [u8; 20], left: u64) -> Self {
+        Self {
+            info_hash,
+            peer_id: PeerId::session(),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: None,
+            numwant: None,
+            key: None,
+            ip: None,
+            no_peer_id: false,
+        }
+    }
+
+    /// Account for verified data received from peers.
+    pub fn record_downloaded(&mut self, bytes: u64) {
+        self.downloaded += bytes;
+        self.left = self.left.saturating_sub(bytes);
+    }
+
+    /// Account for data sent to peers.
+    pub fn record_uploaded(&mut self, bytes: u64) {
+        self.uploaded += bytes;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    /// First announce of a download
+    Started,
+    /// The download just finished, not sent when starting with everything already there
+    Completed,
+    /// We're leaving the swarm
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+/// Announce to every tracker of the torrent at once, and merge the peers they hand out.
+pub fn track(torrent_file: &TorrentFile) -> Result<SwarmResponse> {
+    let info_hash = torrent_file.info.hash_info().context("hash info")?;
+    track_blocking(
+        &torrent_file.trackers(),
+        &AnnounceParams::new(info_hash, torrent_file.info.length),
+        TRACKER_TIMEOUT,
+    )
+}
+
+/// Announce to a tracker, the protocol is picked from the announce URL scheme.
+pub fn announce(announce_url: &str, params: &AnnounceParams) -> Result<TrackerResponse> {
+    announce_blocking(announce_url, params)
+}
+
+/// Derive the scrape URL of a tracker: the last path segment of the announce URL has to start
+/// with `announce`, which is replaced by `scrape`. Other trackers don't support scraping.
+pub fn scrape_url(announce_url: &str) -> Option<String> {
+    let (path, query) = match announce_url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (announce_url, None),
+    };
+    let segment_start = path.rfind('/')? + 1;
+    let rest = path[segment_start..].strip_prefix("announce")?;
+    let mut url = format!("{}scrape{}", &path[..segment_start], rest);
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
     }
+    Some(url)
 }
 
-pub fn track(torrent_file: &TorrentFile) -> Result<TrackerResponse> {
-    let url = get_request_url(&torrent_file).context("get url")?;
-    let response_in_bytes = &reqwest::blocking::get(url)
+/// Get the swarm statistics of several torrents from a tracker without announcing. Torrents the
+/// tracker doesn't know are missing from the result.
+pub fn scrape(
+    announce_url: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    if announce_url.starts_with(UDP_SCHEME) {
+        let tracker = UdpTracker::new(announce_url).context("set up udp tracker")?;
+        let mut stats = HashMap::new();
+        for chunk in info_hashes.chunks(MAX_UDP_SCRAPE_INFO_HASHES) {
+            stats.extend(chunk.iter().copied().zip(tracker.scrape(chunk)?));
+        }
+        return Ok(stats);
+    }
+
+    let mut url = scrape_url(announce_url)
+        .with_context(|| format!("{} doesn't support scraping", announce_url))?;
+    for (i, info_hash) in info_hashes.iter().enumerate() {
+        let separator = if i == 0 && !url.contains('?') {
+            '?'
+        } else {
+            '&'
+        };
+        url.push(separator);
+        url.push_str(&format!("info_hash={}", url_encode_bytes(info_hash)));
+    }
+    let response_in_bytes = &blocking_client()?
+        .get(url)
+        .send()
         .context("request the url")?
         .bytes()
         .context("read request as bytes")?[..];
-    parse_response(response_in_bytes)
+    parse_scrape_response(response_in_bytes)
+}
+
+// TODO: Make it private while still being available for testing
+pub fn parse_scrape_response(response: &[u8]) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let decoded_value = decode(response).context("decode response")?.1;
+    if let Some(reason) = decoded_value.get_bytes("failure reason") {
+        return Err(TrackerError::Failure(String::from_utf8_lossy(reason).into_owned()).into());
+    }
+
+    let files = decoded_value
+        .get_dict("files")
+        .and_then(|files| files.as_dict())
+        .context("should contain files")?;
+    let mut stats = HashMap::new();
+    for (info_hash, file) in files.iter() {
+        let Some(info_hash) = <[u8; 20]>::try_from(*info_hash).ok() else {
+            bail!("files should be keyed by 20 byte info hashes");
+        };
+        stats.insert(
+            info_hash,
+            ScrapeStats {
+                complete: file.get_int("complete").unwrap_or(0),
+                downloaded: file.get_int("downloaded").unwrap_or(0),
+                incomplete: file.get_int("incomplete").unwrap_or(0),
+            },
+        );
+    }
+    Ok(stats)
+}
+
+pub(crate) fn remember_tracker_id(announce_url: &str, response: &TrackerResponse) {
+    if let Some(tracker_id) = &response.tracker_id {
+        tracker_ids()
+            .lock()
+            .unwrap()
+            .insert(announce_url.to_string(), tracker_id.clone());
+    }
+}
+
+// The tracker id last handed out by each tracker, keyed by announce URL
+fn tracker_ids() -> &'static Mutex<HashMap<String, Vec<u8>>> {
+    static TRACKER_IDS: OnceLock<Mutex<HashMap<String, Vec<u8>>>> = OnceLock::new();
+    TRACKER_IDS.get_or_init(Default::default)
 }
 
 // TODO: Make it private while still being available for testing
 pub fn get_request_url(torrent_file: &TorrentFile) -> Result<String> {
-    let mut url = torrent_file.announce.to_owned();
-    let url_encoded_info_hash: String = torrent_file
-        .info
-        .url_encoded_hash_info()
-        .context("get url encoded hash info")?;
-    url.push_str(&format!("?info_hash={}", url_encoded_info_hash));
-    url.push_str("&peer_id=00112233445566778899");
-    url.push_str("&port=6881");
-    url.push_str("&uploaded=0");
-    url.push_str("&downloaded=0");
-    url.push_str(&format!("&left={}", torrent_file.info.length));
+    let info_hash = torrent_file.info.hash_info().context("get hash info")?;
+    Ok(build_request_url(
+        &torrent_file.announce,
+        &AnnounceParams::new(info_hash, torrent_file.info.length),
+    ))
+}
+
+/// The key sent to every tracker when the caller doesn't pick one. Identifies us across IP
+/// changes, so it stays the same for the whole process.
+pub fn announce_key() -> u32 {
+    static KEY: OnceLock<u32> = OnceLock::new();
+    *KEY.get_or_init(|| random_u64() as u32)
+}
+
+pub(crate) fn build_request_url(announce_url: &str, params: &AnnounceParams) -> String {
+    let mut url = announce_url.to_owned();
+    // Private trackers put a passkey in the announce URL's own query
+    url.push(if url.contains('?') { '&' } else { '?' });
+    url.push_str(&format!(
+        "info_hash={}",
+        url_encode_bytes(&params.info_hash)
+    ));
+    url.push_str(&format!(
+        "&peer_id={}",
+        url_encode_query_value(params.peer_id.as_bytes())
+    ));
+    url.push_str(&format!("&port={}", params.port));
+    url.push_str(&format!("&uploaded={}", params.uploaded));
+    url.push_str(&format!("&downloaded={}", params.downloaded));
+    url.push_str(&format!("&left={}", params.left));
     url.push_str("&compact=1");
-    Ok(url)
+    if params.no_peer_id {
+        url.push_str("&no_peer_id=1");
+    }
+    if let Some(event) = params.event {
+        url.push_str(&format!("&event={}", event.as_str()));
+    }
+    if let Some(numwant) = params.numwant {
+        url.push_str(&format!("&numwant={}", numwant));
+    }
+    url.push_str(&format!(
+        "&key={:08x}",
+        params.key.unwrap_or_else(announce_key)
+    ));
+    if let Some(ip) = params.ip {
+        url.push_str(&format!(
+            "&ip={}",
+            url_encode_query_value(ip.to_string().as_bytes())
+        ));
+    }
+    if let Some(tracker_id) = tracker_ids().lock().unwrap().get(announce_url) {
+        url.push_str(&format!("&trackerid={}", url_encode_bytes(tracker_id)));
+    }
+    url
+}
+
+// Percent-encode everything but the unreserved characters, which stay readable
+fn url_encode_query_value(bytes: &[u8]) -> String {
+    bytes.iter().fold("".to_string(), |mut acc, &byte| {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            acc.push(byte as char);
+        } else {
+            acc.push_str(&url_encode_bytes(&[byte]));
+        }
+        acc
+    })
 }
 
 // TODO: Make it private while still being available for testing
 pub fn parse_response(response: &[u8]) -> Result<TrackerResponse> {
     let decoded_value = decode(response).context("decode response")?.1;
 
-    let mut complete: Option<i64> = None;
-    let mut min_interval: Option<i64> = None;
-    let mut incomplete: Option<i64> = None;
-    let mut interval: Option<i64> = None;
-    let mut peers: Option<Vec<PeerAddr>> = None;
+    // When the tracker fails nothing else is guaranteed to be in the response
+    if let Some(reason) = decoded_value.get_bytes("failure reason") {
+        return Err(TrackerError::Failure(String::from_utf8_lossy(reason).into_owned()).into());
+    }
 
-    if let Decoded::Dictionary(dict) = decoded_value {
-        if let Decoded::Integer(n) = dict.get("complete").context("should contain complete")? {
-            complete = Some(n.to_owned());
-        };
-        if let Decoded::Integer(n) = dict
-            .get("min interval")
-            .context("should contain min_interval")?
-        {
-            min_interval = Some(n.to_owned());
-        };
-        if let Decoded::Integer(n) = dict
-            .get("incomplete")
-            .context("should contain incomplete")?
-        {
-            incomplete = Some(n.to_owned());
-        };
-        if let Decoded::Integer(n) = dict.get("interval").context("should contain interval")? {
-            interval = Some(n.to_owned());
-        };
-        if let Decoded::String(info) = dict.get("peers").context("should contain peers")? {
-            let mut vec: Vec<PeerAddr> = vec![];
-            for chunk in info.chunks(6) {
-                vec.push(PeerAddr {
-                    ip: Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
-                    port: ((chunk[4] as u16) << 8) | chunk[5] as u16,
-                })
+    // Peers come compact (a string of 6 byte entries) or as a list of dictionaries, and IPv6
+    // peers may come in a separate compact peers6 string (BEP 7)
+    let mut peer_addr_list: Vec<PeerAddr> = vec![];
+    let mut peer_hosts: Vec<PeerHost> = vec![];
+    if let Some(peers) = decoded_value.get("peers") {
+        if let Some(compact) = peers.as_bytes() {
+            peer_addr_list.extend(parse_compact_peers(compact));
+        } else if let Some(list) = peers.as_list() {
+            for peer in list.iter().filter_map(parse_peer_dictionary) {
+                match peer {
+                    Result::Ok(peer_addr) => peer_addr_list.push(peer_addr),
+                    Err(peer_host) => peer_hosts.push(peer_host),
+                }
             }
-            peers = Some(vec);
+        } else {
+            bail!("peers should be a string or a list");
         }
     }
+    if let Some(peers6) = decoded_value.get_bytes("peers6") {
+        peer_addr_list.extend(parse_compact_peers6(peers6));
+    }
 
     Ok(TrackerResponse {
-        complete: complete.context("get complete")?,
-        min_interval: min_interval.context("get min interval")?,
-        incomplete: incomplete.context("get incomplete")?,
-        interval: interval.context("get interval")?,
-        peer_addr_list: peers.context("get peers")?,
+        complete: decoded_value.get_int("complete"),
+        min_interval: decoded_value.get_int("min interval"),
+        incomplete: decoded_value.get_int("incomplete"),
+        interval: decoded_value
+            .get_int("interval")
+            .context("should contain interval")?,
+        peer_addr_list,
+        peer_hosts,
+        warning_message: decoded_value
+            .get_bytes("warning message")
+            .map(|message| String::from_utf8_lossy(message).into_owned()),
+        tracker_id: decoded_value
+            .get_bytes("tracker id")
+            .map(|tracker_id| tracker_id.to_vec()),
+    })
+}
+
+/// Parse peers in the compact format, 4 bytes of IPv4 address then 2 bytes of port each.
+pub fn parse_compact_peers(peers: &[u8]) -> Vec<PeerAddr> {
+    peers
+        .chunks_exact(6)
+        .map(|chunk| {
+            let ip: [u8; 4] = chunk[..4].try_into().unwrap();
+            PeerAddr::new(SocketAddr::from((
+                ip,
+                u16::from_be_bytes([chunk[4], chunk[5]]),
+            )))
+        })
+        .collect()
+}
+
+/// Parse peers in the compact IPv6 format, 16 bytes of address then 2 bytes of port each.
+pub fn parse_compact_peers6(peers: &[u8]) -> Vec<PeerAddr> {
+    peers
+        .chunks_exact(18)
+        .map(|chunk| {
+            let ip: [u8; 16] = chunk[..16].try_into().unwrap();
+            PeerAddr::new(SocketAddr::from((
+                ip,
+                u16::from_be_bytes([chunk[16], chunk[17]]),
+            )))
+        })
+        .collect()
+}
+
+/// Parse a `{"peer id", "ip", "port"}` entry of a non-compact peer list. The ip may be an IPv4
+/// or IPv6 address, or a hostname which is left for the caller to look up. Unusable entries are
+/// skipped.
+fn parse_peer_dictionary(peer: &Decoded) -> Option<Result<PeerAddr, PeerHost>> {
+    let ip = peer.get_str("ip")?;
+    let port = u16::try_from(peer.get_int("port")?).ok()?;
+    let peer_id = peer
+        .get_bytes("peer id")
+        .and_then(|peer_id| peer_id.try_into().ok());
+    Some(match ip.parse::<IpAddr>() {
+        Result::Ok(ip) => Result::Ok(PeerAddr {
+            addr: SocketAddr::new(ip, port),
+            peer_id,
+        }),
+        Err(_) => Err(PeerHost {
+            host: ip.to_string(),
+            port,
+            peer_id,
+        }),
     })
 }
@@ -0,0 +1,362 @@
+use crate::decoder::{Dictionary, OwnedDecoded};
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+// Requests are only a line and a few headers, anything bigger isn't a client of ours
+const MAX_REQUEST_SIZE: u64 = 8192;
+// Clients sending nothing for this long are hung up on, so they can't keep a thread forever
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    /// How often peers should announce
+    pub interval: Duration,
+    /// Peers that didn't announce for this long are forgotten
+    pub peer_timeout: Duration,
+    /// Only track these info hashes, or any when unset
+    pub whitelist: Option<HashSet<[u8; 20]>>,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1800),
+            peer_timeout: Duration::from_secs(3600),
+            whitelist: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SwarmPeer {
+    addr: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], SwarmPeer>,
+    // How many peers announced a completed download
+    downloaded: i64,
+}
+
+impl Swarm {
+    fn complete(&self) -> i64 {
+        self.peers.values().filter(|peer| peer.left == 0).count() as i64
+    }
+
+    fn incomplete(&self) -> i64 {
+        self.peers.len() as i64 - self.complete()
+    }
+}
+
+/// An in-memory HTTP tracker, answering announces and scrapes with the peers of each swarm.
+pub struct TrackerServer {
+    config: TrackerConfig,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+impl TrackerServer {
+    pub fn new(config: TrackerConfig) -> Self {
+        Self {
+            config,
+            swarms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accept connections until the listener fails, answering each on its own thread.
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream.context("accept connection")?;
+            let tracker = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = tracker.handle_connection(stream) {
+                    eprintln!("failed to answer tracker request: {:#}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        let remote_ip = stream.peer_addr().context("get peer address")?.ip();
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .context("set read timeout")?;
+        // Lines are only read up to the limit, so one without a newline can't take all memory
+        let mut reader = BufReader::new(
+            stream
+                .try_clone()
+                .context("clone stream")?
+                .take(MAX_REQUEST_SIZE),
+        );
+        let mut request_line = String::new();
+        reader
+            .read_line(&mut request_line)
+            .context("read request line")?;
+        // Skip the headers, nothing in them matters to us
+        loop {
+            let mut header = String::new();
+            let length = reader.read_line(&mut header).context("read header")?;
+            if length == 0 || header == "\r\n" || header == "\n" {
+                break;
+            }
+        }
+        if reader.get_ref().limit() == 0 {
+            bail!("request is too large");
+        }
+
+        let target = request_line
+            .strip_prefix("GET ")
+            .and_then(|rest| rest.split(' ').next())
+            .unwrap_or("");
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let (status, body) = match path {
+            "/announce" => ("200 OK", self.announce(query, remote_ip)),
+            "/scrape" => ("200 OK", self.scrape(query)),
+            _ => ("404 Not Found", failure("not found")),
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        )
+        .context("write response head")?;
+        stream.write_all(&body).context("write response body")?;
+        Ok(())
+    }
+
+    /// Record the announcing peer and answer with the other peers of its swarm, as a bencoded
+    /// response. Problems with the request are reported as a failure reason.
+    pub fn announce(&self, query: &str, remote_ip: IpAddr) -> Vec<u8> {
+        match self.try_announce(query, remote_ip) {
+            Some(response) => response,
+            None => failure("invalid announce request"),
+        }
+    }
+
+    fn try_announce(&self, query: &str, remote_ip: IpAddr) -> Option<Vec<u8>> {
+        let params = parse_query(query);
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_slice())
+        };
+        let param_str = |name: &str| param(name).and_then(|value| std::str::from_utf8(value).ok());
+
+        let info_hash: [u8; 20] = param("info_hash")?.try_into().ok()?;
+        let peer_id: [u8; 20] = param("peer_id")?.try_into().ok()?;
+        let port: u16 = param_str("port")?.parse().ok()?;
+        let left: u64 = param_str("left").and_then(|left| left.parse().ok())?;
+        let event = param_str("event").unwrap_or("");
+        let compact = param_str("compact") != Some("0");
+        let no_peer_id = param_str("no_peer_id") == Some("1");
+        let numwant = param_str("numwant")
+            .and_then(|numwant| numwant.parse().ok())
+            .unwrap_or(DEFAULT_NUMWANT)
+            .min(MAX_NUMWANT);
+        let ip = param_str("ip")
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .unwrap_or(remote_ip)
+            .to_canonical();
+
+        if !self.is_allowed(&info_hash) {
+            return Some(failure("info hash is not allowed on this tracker"));
+        }
+
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(info_hash).or_default();
+        self.expire(swarm);
+        if event == "stopped" {
+            swarm.peers.remove(&peer_id);
+        } else {
+            if event == "completed" {
+                swarm.downloaded += 1;
+            }
+            swarm.peers.insert(
+                peer_id,
+                SwarmPeer {
+                    addr: SocketAddr::new(ip, port),
+                    left,
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+
+        let others = swarm
+            .peers
+            .iter()
+            .filter(|(other_id, _)| **other_id != peer_id)
+            .take(numwant);
+        let mut response = vec![
+            ("complete", OwnedDecoded::Integer(swarm.complete())),
+            ("incomplete", OwnedDecoded::Integer(swarm.incomplete())),
+            (
+                "interval",
+                OwnedDecoded::Integer(self.config.interval.as_secs() as i64),
+            ),
+        ];
+        if compact {
+            // IPv4 peers go in peers and IPv6 peers in peers6 (BEP 7)
+            let mut peers: Vec<u8> = vec![];
+            let mut peers6: Vec<u8> = vec![];
+            for (_, peer) in others {
+                match peer.addr {
+                    SocketAddr::V4(addr) => {
+                        peers.extend(addr.ip().octets());
+                        peers.extend(addr.port().to_be_bytes());
+                    }
+                    SocketAddr::V6(addr) => {
+                        peers6.extend(addr.ip().octets());
+                        peers6.extend(addr.port().to_be_bytes());
+                    }
+                }
+            }
+            response.push(("peers", OwnedDecoded::String(peers)));
+            if !peers6.is_empty() {
+                response.push(("peers6", OwnedDecoded::String(peers6)));
+            }
+        } else {
+            let peers = others
+                .map(|(other_id, peer)| {
+                    let mut entry = vec![
+                        (
+                            "ip",
+                            OwnedDecoded::String(peer.addr.ip().to_string().into_bytes()),
+                        ),
+                        ("port", OwnedDecoded::Integer(peer.addr.port() as i64)),
+                    ];
+                    if !no_peer_id {
+                        entry.push(("peer id", OwnedDecoded::String(other_id.to_vec())));
+                    }
+                    dictionary(entry)
+                })
+                .collect();
+            response.push(("peers", OwnedDecoded::Array(peers)));
+        }
+        Some(dictionary(response).encode())
+    }
+
+    /// Answer with the statistics of the requested swarms, or of every swarm when no info hash
+    /// is given.
+    pub fn scrape(&self, query: &str) -> Vec<u8> {
+        let requested: Vec<[u8; 20]> = parse_query(query)
+            .into_iter()
+            .filter(|(key, _)| key == "info_hash")
+            .filter_map(|(_, value)| value.try_into().ok())
+            .collect();
+
+        let mut swarms = self.swarms.lock().unwrap();
+        for swarm in swarms.values_mut() {
+            self.expire(swarm);
+        }
+        let mut info_hashes: Vec<[u8; 20]> = if requested.is_empty() {
+            swarms.keys().copied().collect()
+        } else {
+            requested
+        };
+        // Bencoded dictionaries are sorted by key
+        info_hashes.sort();
+        info_hashes.dedup();
+
+        let mut files = Dictionary::new();
+        for info_hash in info_hashes {
+            if !self.is_allowed(&info_hash) {
+                continue;
+            }
+            let Some(swarm) = swarms.get(&info_hash) else {
+                continue;
+            };
+            files.insert(
+                info_hash.to_vec(),
+                dictionary(vec![
+                    ("complete", OwnedDecoded::Integer(swarm.complete())),
+                    ("downloaded", OwnedDecoded::Integer(swarm.downloaded)),
+                    ("incomplete", OwnedDecoded::Integer(swarm.incomplete())),
+                ]),
+            );
+        }
+        dictionary(vec![("files", OwnedDecoded::Dictionary(files))]).encode()
+    }
+
+    fn is_allowed(&self, info_hash: &[u8; 20]) -> bool {
+        self.config
+            .whitelist
+            .as_ref()
+            .is_none_or(|whitelist| whitelist.contains(info_hash))
+    }
+
+    fn expire(&self, swarm: &mut Swarm) {
+        swarm
+            .peers
+            .retain(|_, peer| peer.last_seen.elapsed() < self.config.peer_timeout);
+    }
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    dictionary(vec![(
+        "failure reason",
+        OwnedDecoded::String(reason.as_bytes().to_vec()),
+    )])
+    .encode()
+}
+
+// Build a dictionary with its keys sorted, as bencode requires
+fn dictionary(mut entries: Vec<(&str, OwnedDecoded)>) -> OwnedDecoded {
+    entries.sort_by_key(|(key, _)| *key);
+    OwnedDecoded::Dictionary(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+// Split a query string into its percent-decoded keys and values. Values stay bytes since info
+// hashes and peer ids are binary.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                String::from_utf8_lossy(&percent_decode(key)).into_owned(),
+                percent_decode(value),
+            )
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => match hex::decode(&bytes[i + 1..i + 3]).ok() {
+                Some(byte) => {
+                    decoded.extend(byte);
+                    i += 3;
+                    continue;
+                }
+                None => decoded.push(b'%'),
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
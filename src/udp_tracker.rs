@@ -0,0 +1,267 @@
+use crate::random::random_u64;
+use crate::tracker::{
+    announce_key, parse_compact_peers, parse_compact_peers6, AnnounceEvent, AnnounceParams,
+    ScrapeStats, TrackerError, TrackerResponse,
+};
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+pub const UDP_SCHEME: &str = "udp://";
+
+// Magic constant identifying the protocol in connect requests (BEP 15)
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// A connection ID can be reused for a minute after it was received
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+// Wait 15 * 2 ^ n seconds for a response before retransmitting, for n up to 8
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRANSMISSIONS: u32 = 8;
+// More info hashes than this don't fit in a single scrape request
+pub const MAX_UDP_SCRAPE_INFO_HASHES: usize = 74;
+const MAX_PACKET_SIZE: usize = 65536;
+
+// Connection IDs by tracker, shared by every announce and scrape of the process
+static CONNECTION_IDS: OnceLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> = OnceLock::new();
+
+/// A client for a `udp://` tracker speaking the UDP tracker protocol (BEP 15).
+pub struct UdpTracker {
+    tracker_addr: SocketAddr,
+    socket: UdpSocket,
+    base_timeout: Duration,
+    max_retransmissions: u32,
+}
+
+impl UdpTracker {
+    pub fn new(announce: &str) -> Result<Self> {
+        let url = reqwest::Url::parse(announce).context("parse announce url")?;
+        if url.scheme() != "udp" {
+            bail!("{} isn't a udp tracker", announce);
+        }
+        let host = url
+            .host_str()
+            .context("announce url should contain a host")?;
+        let port = url.port().context("announce url should contain a port")?;
+        let tracker_addr = (host, port)
+            .to_socket_addrs()
+            .context("resolve tracker address")?
+            .next()
+            .context("tracker host has no address")?;
+
+        let bind_addr = if tracker_addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).context("bind udp socket")?;
+        // Only accept datagrams coming from the tracker
+        socket
+            .connect(tracker_addr)
+            .context("connect udp socket to tracker")?;
+
+        Ok(Self {
+            tracker_addr,
+            socket,
+            base_timeout: BASE_TIMEOUT,
+            max_retransmissions: MAX_RETRANSMISSIONS,
+        })
+    }
+
+    /// Replace the 15 * 2 ^ n second retransmission schedule, e.g. to give up sooner.
+    pub fn with_timeouts(mut self, base_timeout: Duration, max_retransmissions: u32) -> Self {
+        self.base_timeout = base_timeout;
+        self.max_retransmissions = max_retransmissions;
+        self
+    }
+
+    pub fn announce(&self, params: &AnnounceParams) -> Result<TrackerResponse> {
+        let mut body = BytesMut::with_capacity(82);
+        body.put_slice(&params.info_hash);
+        body.put_slice(params.peer_id.as_bytes());
+        body.put_u64(params.downloaded);
+        body.put_u64(params.left);
+        body.put_u64(params.uploaded);
+        body.put_u32(match params.event {
+            None => 0,
+            Some(AnnounceEvent::Completed) => 1,
+            Some(AnnounceEvent::Started) => 2,
+            Some(AnnounceEvent::Stopped) => 3,
+        });
+        // Only IPv4 addresses fit, 0 means the address the request came from
+        body.put_u32(match params.ip {
+            Some(IpAddr::V4(ip)) => ip.into(),
+            _ => 0,
+        });
+        body.put_u32(params.key.unwrap_or_else(announce_key));
+        // -1 means the tracker's default
+        body.put_i32(params.numwant.map_or(-1, |numwant| numwant as i32));
+        body.put_u16(params.port);
+
+        let response = self
+            .request(ACTION_ANNOUNCE, &body)
+            .context("announce to udp tracker")?;
+        if response.len() < 12 {
+            bail!("announce response is too short");
+        }
+        let mut response = &response[..];
+        let interval = response.get_u32() as i64;
+        let leechers = response.get_u32() as i64;
+        let seeders = response.get_u32() as i64;
+
+        Ok(TrackerResponse {
+            complete: Some(seeders),
+            min_interval: None,
+            incomplete: Some(leechers),
+            interval,
+            // Trackers reached over IPv6 answer with IPv6 peers
+            peer_addr_list: if self.tracker_addr.is_ipv6() {
+                parse_compact_peers6(response)
+            } else {
+                parse_compact_peers(response)
+            },
+            peer_hosts: vec![],
+            warning_message: None,
+            tracker_id: None,
+        })
+    }
+
+    /// Scrape the statistics of several torrents at once, in the order of `info_hashes`.
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        if info_hashes.is_empty() || info_hashes.len() > MAX_UDP_SCRAPE_INFO_HASHES {
+            bail!(
+                "can scrape between 1 and {} info hashes at once, got {}",
+                MAX_UDP_SCRAPE_INFO_HASHES,
+                info_hashes.len()
+            );
+        }
+        let body: Vec<u8> = info_hashes.concat();
+
+        let response = self
+            .request(ACTION_SCRAPE, &body)
+            .context("scrape udp tracker")?;
+        if response.len() < 12 * info_hashes.len() {
+            bail!("scrape response is too short");
+        }
+        Ok(response
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|mut chunk| ScrapeStats {
+                complete: chunk.get_u32() as i64,
+                downloaded: chunk.get_u32() as i64,
+                incomplete: chunk.get_u32() as i64,
+            })
+            .collect())
+    }
+
+    fn request(&self, action: u32, body: &[u8]) -> Result<Vec<u8>> {
+        let response = self.transact(|| self.connection_id(), action, body);
+        if response.is_err() {
+            // The tracker might have forgotten our connection ID, get a fresh one next time
+            connection_ids().lock().unwrap().remove(&self.tracker_addr);
+        }
+        response
+    }
+
+    fn connection_id(&self) -> Result<u64> {
+        if let Some((connection_id, received_at)) =
+            connection_ids().lock().unwrap().get(&self.tracker_addr)
+        {
+            if received_at.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(*connection_id);
+            }
+        }
+
+        let response = self
+            .transact(|| Ok(PROTOCOL_ID), ACTION_CONNECT, &[])
+            .context("connect to udp tracker")?;
+        if response.len() < 8 {
+            bail!("connect response is too short");
+        }
+        let connection_id = (&response[..8]).get_u64();
+        connection_ids()
+            .lock()
+            .unwrap()
+            .insert(self.tracker_addr, (connection_id, Instant::now()));
+        Ok(connection_id)
+    }
+
+    /// Send a request until a response with the same transaction ID arrives, and return the
+    /// response without its action and transaction ID. The connection ID is fetched again for
+    /// every retransmission since it may expire while we wait.
+    fn transact(
+        &self,
+        connection_id: impl Fn() -> Result<u64>,
+        action: u32,
+        body: &[u8],
+    ) -> Result<Vec<u8>> {
+        let transaction_id = random_u64() as u32;
+        let mut response = vec![0; MAX_PACKET_SIZE];
+        for n in 0..=self.max_retransmissions {
+            let mut request = BytesMut::with_capacity(16 + body.len());
+            request.put_u64(connection_id()?);
+            request.put_u32(action);
+            request.put_u32(transaction_id);
+            request.put_slice(body);
+            self.socket
+                .send(&request)
+                .context("send request to udp tracker")?;
+
+            let deadline = Instant::now() + self.base_timeout * 2u32.pow(n);
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                self.socket
+                    .set_read_timeout(Some(deadline - now))
+                    .context("set read timeout")?;
+                let length = match self.socket.recv(&mut response) {
+                    Ok(length) => length,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        break;
+                    }
+                    Err(e) => return Err(e).context("receive response from udp tracker"),
+                };
+                if length < 8 {
+                    continue;
+                }
+                let mut header = &response[..8];
+                let response_action = header.get_u32();
+                // Late answers to an earlier request, or spoofed ones, don't count
+                if header.get_u32() != transaction_id {
+                    continue;
+                }
+                if response_action == ACTION_ERROR {
+                    return Err(TrackerError::Failure(
+                        String::from_utf8_lossy(&response[8..length]).into_owned(),
+                    )
+                    .into());
+                }
+                if response_action != action {
+                    bail!(
+                        "udp tracker answered action {} to action {}",
+                        response_action,
+                        action
+                    );
+                }
+                return Ok(response[8..length].to_vec());
+            }
+        }
+        bail!(
+            "udp tracker didn't respond after {} retransmissions",
+            self.max_retransmissions
+        )
+    }
+}
+
+fn connection_ids() -> &'static Mutex<HashMap<SocketAddr, (u64, Instant)>> {
+    CONNECTION_IDS.get_or_init(Default::default)
+}
@@ -0,0 +1,103 @@
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::announcer::Announcer;
+use bittorrent_starter_rust::tracker::{AnnounceParams, TrackerResponse};
+
+mod common;
+use common::spawn_http_tracker;
+
+// Answer every announce with a single peer
+fn spawn_tracker() -> (String, mpsc::Receiver<Vec<String>>) {
+    spawn_http_tracker(b"d8:intervali3600e12:min intervali1e5:peers6:\x0a\x00\x00\x01\x1a\xe1e")
+}
+
+fn first_response(interval: i64, min_interval: Option<i64>) -> TrackerResponse {
+    TrackerResponse {
+        complete: None,
+        min_interval,
+        incomplete: None,
+        interval,
+        peer_addr_list: vec![],
+        peer_hosts: vec![],
+        warning_message: None,
+        tracker_id: None,
+    }
+}
+
+#[test]
+fn reannounce_on_the_tracker_interval() {
+    let (announce_url, requests) = spawn_tracker();
+    let (peers_tx, peers_rx) = mpsc::channel();
+    let announcer = Announcer::start(
+        announce_url,
+        AnnounceParams::new([3; 20], 100),
+        &first_response(1, None),
+        move |peers| peers_tx.send(peers).unwrap(),
+    );
+    announcer.record_downloaded(40);
+
+    let peers = peers_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(peers[0].addr.to_string(), "10.0.0.1:6881");
+    let request_line = &requests.recv().unwrap()[0];
+    assert!(request_line.contains("&downloaded=40&left=60&"));
+
+    let params = announcer.stop();
+    assert_eq!(params.downloaded, 40);
+}
+
+#[test]
+fn announce_early_when_asked() {
+    let (announce_url, _requests) = spawn_tracker();
+    let (peers_tx, peers_rx) = mpsc::channel();
+    let announcer = Announcer::start(
+        announce_url,
+        AnnounceParams::new([4; 20], 100),
+        &first_response(3600, Some(1)),
+        move |peers| peers_tx.send(peers).unwrap(),
+    );
+
+    // Nothing happens before the interval is over
+    assert!(peers_rx.recv_timeout(Duration::from_millis(1500)).is_err());
+
+    // The min interval is over by now, so the announce goes out right away
+    announcer.announce_soon();
+    assert!(peers_rx.recv_timeout(Duration::from_secs(1)).is_ok());
+
+    // The min interval starts over after each announce
+    announcer.announce_soon();
+    assert!(peers_rx.recv_timeout(Duration::from_millis(500)).is_err());
+    assert!(peers_rx.recv_timeout(Duration::from_secs(2)).is_ok());
+    announcer.stop();
+}
+
+#[test]
+fn cope_with_a_huge_interval() {
+    let (announce_url, _requests) = spawn_tracker();
+    let announcer = Announcer::start(
+        announce_url,
+        AnnounceParams::new([5; 20], 100),
+        &first_response(i64::MAX, Some(i64::MAX)),
+        |_| {},
+    );
+    announcer.stop();
+}
+
+#[test]
+fn stop_soon_while_a_udp_tracker_doesnt_answer() {
+    let silent_tracker = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let announcer = Announcer::start(
+        format!("udp://{}", silent_tracker.local_addr().unwrap()),
+        AnnounceParams::new([6; 20], 100),
+        &first_response(1, None),
+        |_| {},
+    );
+
+    // Stop while the re-announce is waiting for an answer
+    thread::sleep(Duration::from_millis(1500));
+    let stopping = Instant::now();
+    announcer.stop();
+    assert!(stopping.elapsed() < Duration::from_secs(10));
+}
@@ -0,0 +1,118 @@
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::async_tracker::{track, track_blocking};
+use bittorrent_starter_rust::tracker::{AnnounceParams, PeerAddr};
+
+mod common;
+use common::spawn_http_tracker;
+
+// Never answer, and report when the client hangs up
+fn spawn_silent_tracker() -> (String, mpsc::Receiver<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let announce_url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut buf = [0; 1024];
+                while stream.read(&mut buf).map(|n| n > 0).unwrap_or(false) {}
+                let _ = tx.send(());
+            });
+        }
+    });
+    (announce_url, rx)
+}
+
+fn peer(last_byte: u8, port: u16) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::new(10, 0, 0, last_byte), port))
+}
+
+#[test]
+fn merge_the_peers_of_every_tracker() {
+    let (compact, _) = spawn_http_tracker(b"d8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe1e");
+    let (non_compact, _) = spawn_http_tracker(
+        b"d8:intervali900e5:peersld2:ip8:10.0.0.17:peer id20:-AA0001-aaaaaaaaaaaa4:porti6881eed2:ip8:10.0.0.24:porti6882eeee",
+    );
+    let (silent, _) = spawn_silent_tracker();
+
+    let started = Instant::now();
+    let swarm = track_blocking(
+        &[compact.clone(), silent.clone(), non_compact.clone()],
+        &AnnounceParams::new([8; 20], 100),
+        Duration::from_millis(500),
+    )
+    .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    assert_eq!(
+        swarm.peer_addr_list,
+        vec![
+            PeerAddr {
+                addr: peer(1, 6881),
+                peer_id: Some(*b"-AA0001-aaaaaaaaaaaa"),
+            },
+            PeerAddr::new(peer(2, 6882)),
+        ]
+    );
+    let answered: Vec<&String> = swarm.responses.iter().map(|(url, _)| url).collect();
+    assert_eq!(answered, vec![&compact, &non_compact]);
+    assert_eq!(swarm.failures.len(), 1);
+    assert_eq!(swarm.failures[0].0, silent);
+}
+
+#[test]
+fn fail_when_no_tracker_answers() {
+    let (silent, _) = spawn_silent_tracker();
+    let (failing, _) = spawn_http_tracker(b"d14:failure reason9:forbiddene");
+
+    assert!(track_blocking(
+        &[silent, failing],
+        &AnnounceParams::new([8; 20], 100),
+        Duration::from_millis(200),
+    )
+    .is_err());
+    assert!(track_blocking(
+        &[],
+        &AnnounceParams::new([8; 20], 100),
+        Duration::from_secs(1)
+    )
+    .is_err());
+}
+
+#[test]
+fn give_up_on_a_silent_udp_tracker() {
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let started = Instant::now();
+    assert!(track_blocking(
+        &[format!("udp://{}", silent.local_addr().unwrap())],
+        &AnnounceParams::new([8; 20], 100),
+        Duration::from_millis(300),
+    )
+    .is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn cancel_announces_when_dropped() {
+    let (silent, hung_up) = spawn_silent_tracker();
+
+    let announce_urls = [silent];
+    let params = AnnounceParams::new([8; 20], 100);
+    let announces = track(&announce_urls, &params, Duration::from_secs(60));
+    assert!(tokio::time::timeout(Duration::from_millis(200), announces)
+        .await
+        .is_err());
+
+    // Dropping the announces closed their connections
+    tokio::task::spawn_blocking(move || hung_up.recv_timeout(Duration::from_secs(5)))
+        .await
+        .unwrap()
+        .unwrap();
+}
@@ -0,0 +1,45 @@
+use bittorrent_starter_rust::bitfield::{Bitfield, BitfieldError};
+
+#[test]
+fn parse_bitfield_payloads() {
+    let bitfield = Bitfield::from_payload(&[0b1010_0000, 0b0100_0000], 10).unwrap();
+    let had: Vec<usize> = (0..12).filter(|&i| bitfield.has(i)).collect();
+    assert_eq!(had, [0, 2, 9]);
+    assert_eq!(bitfield.count(), 3);
+    assert!(!bitfield.is_complete());
+
+    assert!(Bitfield::full(10).is_complete());
+    assert_eq!(Bitfield::full(10).as_bytes(), [0xff, 0b1100_0000]);
+    assert_eq!(Bitfield::new(10).count(), 0);
+}
+
+#[test]
+fn reject_invalid_bitfield_payloads() {
+    assert_eq!(
+        Bitfield::from_payload(&[0xff], 10),
+        Err(BitfieldError::WrongLength {
+            expected: 2,
+            got: 1
+        })
+    );
+    assert_eq!(
+        Bitfield::from_payload(&[0xff, 0b1110_0000], 10),
+        Err(BitfieldError::SpareBitsSet)
+    );
+    assert!(Bitfield::from_payload(&[0xff, 0xff], 16).is_ok());
+}
+
+#[test]
+fn set_pieces_on_have() {
+    let mut bitfield = Bitfield::new(10);
+    bitfield.set(9).unwrap();
+    assert!(bitfield.has(9));
+    assert_eq!(bitfield.as_bytes(), [0, 0b0100_0000]);
+    assert_eq!(
+        bitfield.set(10),
+        Err(BitfieldError::PieceOutOfRange {
+            piece_index: 10,
+            piece_count: 10
+        })
+    );
+}
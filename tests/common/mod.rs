@@ -0,0 +1,214 @@
+//! Stand-in servers and torrents shared by the integration tests.
+
+// Every test binary compiles this module but only uses some of it
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use bittorrent_starter_rust::bitfield::Bitfield;
+use bittorrent_starter_rust::handshake::Handshake;
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_message::PeerMessage;
+use bittorrent_starter_rust::torrent_file::{TorrentFile, TorrentFileInfo};
+use bittorrent_starter_rust::tracker::{announce, AnnounceParams};
+use bittorrent_starter_rust::tracker_server::{TrackerConfig, TrackerServer};
+use sha1::{Digest, Sha1};
+
+/// How long the pieces of [`torrent_file_of`] torrents are, two blocks.
+pub const PIECE_LENGTH: usize = 1 << 15;
+
+/// Answer each connection with `response`, a whole HTTP response, or never when it's empty.
+/// Returns the server's address and the request heads, request line first.
+pub fn spawn_http_server(response: impl Into<Vec<u8>>) -> (String, mpsc::Receiver<Vec<String>>) {
+    let response = response.into();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut head = vec![];
+            let mut reader = BufReader::new(&stream);
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                head.push(line.trim_end().to_string());
+            }
+            let _ = tx.send(head);
+            if response.is_empty() {
+                // Never answer
+                thread::sleep(Duration::from_secs(5));
+                continue;
+            }
+            let _ = stream.write_all(&response);
+        }
+    });
+    (addr, rx)
+}
+
+/// A 200 response carrying `body`.
+pub fn http_ok(body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend(body);
+    response
+}
+
+/// An HTTP tracker answering every request with the bencoded `body`. Returns its announce URL
+/// and the request heads.
+pub fn spawn_http_tracker(body: &[u8]) -> (String, mpsc::Receiver<Vec<String>>) {
+    let (addr, heads) = spawn_http_server(http_ok(body));
+    (format!("http://{}/announce", addr), heads)
+}
+
+/// A real tracker, for tests needing peers to find each other. Returns its announce URL.
+pub fn spawn_tracker_server(config: TrackerConfig) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let announce_url = format!("http://{}/announce", listener.local_addr().unwrap());
+    thread::spawn(move || Arc::new(TrackerServer::new(config)).serve(listener));
+    announce_url
+}
+
+/// A single file torrent of `data`, announced to `announce`.
+pub fn torrent_file_of(announce: impl Into<String>, data: &[u8]) -> TorrentFile {
+    TorrentFile {
+        announce: announce.into(),
+        announce_list: vec![],
+        info: TorrentFileInfo {
+            name: "sample.txt".to_string(),
+            piece_length: PIECE_LENGTH as u64,
+            pieces: data
+                .chunks(PIECE_LENGTH)
+                .flat_map(|piece| <[u8; 20]>::from(Sha1::digest(piece)))
+                .collect(),
+            length: data.len() as u64,
+            files: None,
+            info_hash: None,
+        },
+    }
+}
+
+/// A listener announced to the torrent's tracker as the peer `peer_id`, for a stand-in peer.
+pub fn announced_listener(torrent_file: &TorrentFile, peer_id: PeerId) -> TcpListener {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut params = AnnounceParams::new(torrent_file.info.hash_info().unwrap(), 0);
+    params.peer_id = peer_id;
+    params.port = listener.local_addr().unwrap().port();
+    announce(&torrent_file.announce, &params).unwrap();
+    listener
+}
+
+/// Answer the handshake of a peer connecting to a stand-in peer.
+pub fn answer_handshake(stream: &mut TcpStream, info_hash: [u8; 20], peer_id: PeerId) {
+    Handshake::read_from(stream).unwrap();
+    Handshake::new(info_hash, peer_id).write_to(stream).unwrap();
+}
+
+/// A seeder announced to the tracker, counting the connections to it, those we hung up on, and
+/// the blocks it sent.
+pub struct Seeder {
+    pub peer_id: PeerId,
+    pub connections: Arc<AtomicUsize>,
+    pub hung_up: Arc<AtomicUsize>,
+    pub blocks_sent: Arc<AtomicUsize>,
+}
+
+impl Seeder {
+    /// The first `corrupt_blocks` blocks are sent as zeros instead of the data, slow seeders
+    /// wait before every block.
+    pub fn spawn(
+        torrent_file: &TorrentFile,
+        data: &[u8],
+        corrupt_blocks: usize,
+        delay: Duration,
+    ) -> Self {
+        let info_hash = torrent_file.info.hash_info().unwrap();
+        let peer_id = PeerId::generate();
+        let listener = announced_listener(torrent_file, peer_id);
+
+        let seeder = Self {
+            peer_id,
+            connections: Default::default(),
+            hung_up: Default::default(),
+            blocks_sent: Default::default(),
+        };
+        let connections = seeder.connections.clone();
+        let hung_up = seeder.hung_up.clone();
+        let blocks_sent = seeder.blocks_sent.clone();
+        let data = data.to_vec();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                connections.fetch_add(1, Ordering::SeqCst);
+                let data = data.clone();
+                let hung_up = hung_up.clone();
+                let blocks_sent = blocks_sent.clone();
+                thread::spawn(move || {
+                    seed(
+                        stream.unwrap(),
+                        info_hash,
+                        peer_id,
+                        &data,
+                        corrupt_blocks,
+                        delay,
+                        &blocks_sent,
+                    );
+                    hung_up.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        seeder
+    }
+}
+
+// Serve every request from the whole data until we hang up
+fn seed(
+    mut stream: TcpStream,
+    info_hash: [u8; 20],
+    peer_id: PeerId,
+    data: &[u8],
+    corrupt_blocks: usize,
+    delay: Duration,
+    blocks_sent: &AtomicUsize,
+) {
+    answer_handshake(&mut stream, info_hash, peer_id);
+    let piece_count = data.len().div_ceil(PIECE_LENGTH);
+    PeerMessage::Bitfield(Bitfield::full(piece_count).as_bytes().to_vec())
+        .write_to(&mut stream)
+        .unwrap();
+    while let Ok(message) = PeerMessage::read_from(&mut stream) {
+        let answer = match message {
+            PeerMessage::Interested => PeerMessage::Unchoke,
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                thread::sleep(delay);
+                let begin_in_data = index as usize * PIECE_LENGTH + begin as usize;
+                let mut block = data[begin_in_data..begin_in_data + length as usize].to_vec();
+                if blocks_sent.fetch_add(1, Ordering::SeqCst) < corrupt_blocks {
+                    block.fill(0);
+                }
+                PeerMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                }
+            }
+            _ => continue,
+        };
+        if answer.write_to(&mut stream).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs;
+use std::path::PathBuf;
+
+use bittorrent_starter_rust::cross_seed::{find_data, link_data, DataMatch};
+use bittorrent_starter_rust::torrent_file::{
+    parse_torrent_file, TorrentFileEntry, TorrentFileInfo,
+};
+use sha1::{Digest, Sha1};
+
+fn torrent_info_for(name: &str, data: &[u8], piece_length: u64) -> TorrentFileInfo {
+    TorrentFileInfo {
+        name: name.to_string(),
+        piece_length,
+        pieces: data
+            .chunks(piece_length as usize)
+            .flat_map(|chunk| Sha1::digest(chunk).to_vec())
+            .collect(),
+        length: data.len() as u64,
+        files: None,
+        info_hash: None,
+    }
+}
+
+#[test]
+fn find_and_link_matching_data() {
+    let data: Vec<u8> = (0..10_000u32).map(|n| (n * 7 % 251) as u8).collect();
+    let info = torrent_info_for("movie.mkv", &data, 4096);
+
+    let search_dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(search_dir.path().join("a/b")).unwrap();
+    // Same size but different content, must not be picked
+    fs::write(search_dir.path().join("a/decoy.bin"), vec![0u8; data.len()]).unwrap();
+    fs::write(search_dir.path().join("a/b/renamed.bin"), &data).unwrap();
+    fs::write(search_dir.path().join("other.bin"), b"unrelated").unwrap();
+
+    let matches = find_data(&info, search_dir.path()).unwrap();
+    assert_eq!(
+        matches,
+        vec![DataMatch {
+            torrent_path: PathBuf::from("movie.mkv"),
+            local_path: search_dir.path().join("a/b/renamed.bin"),
+            verified_pieces: 3,
+        }]
+    );
+
+    let target_dir = tempfile::tempdir().unwrap();
+    link_data(&matches, target_dir.path()).unwrap();
+    assert_eq!(fs::read(target_dir.path().join("movie.mkv")).unwrap(), data);
+}
+
+#[test]
+fn find_nothing_when_no_data_matches() {
+    let data = vec![1u8; 5000];
+    let info = torrent_info_for("data.bin", &data, 4096);
+
+    let search_dir = tempfile::tempdir().unwrap();
+    fs::write(search_dir.path().join("corrupted.bin"), vec![2u8; 5000]).unwrap();
+
+    assert_eq!(find_data(&info, search_dir.path()).unwrap(), vec![]);
+}
+
+#[test]
+fn find_the_files_of_a_multi_file_torrent() {
+    let data: Vec<u8> = (0..10_000u32).map(|n| (n * 7 % 251) as u8).collect();
+    let torrent = [
+        &b"d8:announce3:url4:infod5:filesld6:lengthi6000e4:pathl5:video9:movie.mkveed6:lengthi4000e4:pathl8:subs.srteee4:name6:series12:piece lengthi2000e6:pieces"[..],
+        format!("{}:", data.len() / 2000 * 20).as_bytes(),
+        &data
+            .chunks(2000)
+            .flat_map(|chunk| Sha1::digest(chunk).to_vec())
+            .collect::<Vec<u8>>(),
+        b"ee",
+    ]
+    .concat();
+    let info = parse_torrent_file(&torrent).unwrap().info;
+    assert_eq!(info.length, 10_000);
+    assert_eq!(
+        info.files(),
+        vec![
+            TorrentFileEntry {
+                path: PathBuf::from("series/video/movie.mkv"),
+                offset: 0,
+                length: 6000,
+            },
+            TorrentFileEntry {
+                path: PathBuf::from("series/subs.srt"),
+                offset: 6000,
+                length: 4000,
+            },
+        ]
+    );
+
+    let search_dir = tempfile::tempdir().unwrap();
+    fs::write(search_dir.path().join("movie.mkv"), &data[..6000]).unwrap();
+    fs::write(search_dir.path().join("subtitles.srt"), &data[6000..]).unwrap();
+    let matches = find_data(&info, search_dir.path()).unwrap();
+    assert_eq!(
+        matches,
+        vec![
+            DataMatch {
+                torrent_path: PathBuf::from("series/video/movie.mkv"),
+                local_path: search_dir.path().join("movie.mkv"),
+                verified_pieces: 3,
+            },
+            DataMatch {
+                torrent_path: PathBuf::from("series/subs.srt"),
+                local_path: search_dir.path().join("subtitles.srt"),
+                verified_pieces: 2,
+            },
+        ]
+    );
+}
+
+#[test]
+fn reject_a_zero_piece_length() {
+    let info = TorrentFileInfo {
+        piece_length: 0,
+        ..torrent_info_for("data.bin", &[1u8; 5000], 4096)
+    };
+    let search_dir = tempfile::tempdir().unwrap();
+    assert!(find_data(&info, search_dir.path()).is_err());
+}
@@ -1,5 +1,6 @@
-use bittorrent_starter_rust::decoder::decode_bencoded_value;
+use bittorrent_starter_rust::decoder::{decode, decode_bencoded_value, Decoded, OwnedDecoded};
 use serde_json::json;
+use std::collections::HashSet;
 
 macro_rules! test_decode {
     ($input:expr, $output:expr) => {
@@ -45,3 +46,152 @@ fn decode_bencoded_dictionaries() {
         json!({"inner_dict":{"key1":"value1","key2":42,"list_key":["item1","item2",3]}})
     );
 }
+
+#[test]
+fn convert_between_borrowed_and_owned() {
+    let input = b"d4:infod6:lengthi92063e4:name10:sample.txte5:peersl2:p12:p2ee".to_vec();
+    let (_, decoded) = decode(&input).unwrap();
+    let owned: OwnedDecoded = decoded.clone().into();
+    assert_eq!(owned.as_decoded(), decoded);
+
+    // The owned value outlives the input and crosses threads
+    drop(input);
+    let owned = std::thread::spawn(move || owned).join().unwrap();
+    assert_eq!(
+        owned.get_dict("info").unwrap().get_str("name"),
+        Some("sample.txt")
+    );
+}
+
+#[test]
+fn access_decoded_values() {
+    let (_, decoded) = decode(b"d3:agei42e4:name5:alice4:tagsl1:a1:be4:rawsi1ee").unwrap();
+    assert_eq!(decoded.get_int("age"), Some(42));
+    assert_eq!(decoded.get_str("name"), Some("alice"));
+    assert_eq!(decoded.get_bytes("name"), Some(&b"alice"[..]));
+    assert_eq!(
+        decoded.get_list("tags").unwrap(),
+        &[Decoded::String(b"a"), Decoded::String(b"b")]
+    );
+    assert!(decoded.get_dict("raws").is_none());
+    assert!(decoded.get_int("name").is_none());
+    assert!(decoded.get_int("missing").is_none());
+    assert!(Decoded::Integer(1).get("age").is_none());
+}
+
+#[test]
+fn hash_decoded_values() {
+    let (_, first) = decode(b"d1:ai1e1:bi2ee").unwrap();
+    let (_, second) = decode(b"d1:bi2e1:ai1ee").unwrap();
+    let mut set: HashSet<OwnedDecoded> = HashSet::new();
+    set.insert(first.into_owned());
+    set.insert(second.into_owned());
+    assert_eq!(set.len(), 1);
+}
+
+#[test]
+fn keep_dictionary_key_order_when_reencoding() {
+    let input = b"d5:zebrai1e5:applel1:xe5:mango3:fooe";
+    let (_, decoded) = decode(input).unwrap();
+    let keys: Vec<&[u8]> = decoded.as_dict().unwrap().keys().copied().collect();
+    assert_eq!(keys, vec![&b"zebra"[..], b"apple", b"mango"]);
+    assert_eq!(decoded.encode(), input);
+    assert_eq!(decoded.into_owned().encode(), input);
+
+    let sample = std::fs::read("sample.torrent").unwrap();
+    assert_eq!(decode(&sample).unwrap().1.encode(), sample);
+}
+
+#[test]
+fn keep_non_utf8_dictionary_keys() {
+    let input = b"d2:\xff\xfei1e4:texti2ee";
+    let (_, decoded) = decode(input).unwrap();
+    assert_eq!(decoded.get_int(b"\xff\xfe"), Some(1));
+    assert_eq!(decoded.get_int("text"), Some(2));
+    assert_eq!(decoded.encode(), input);
+    assert!(decode_bencoded_value(input).is_err());
+}
+
+#[test]
+fn decode_big_integers() {
+    let (_, decoded) = decode(b"i99999999999999999999999e").unwrap();
+    assert_eq!(decoded, Decoded::BigInteger("99999999999999999999999"));
+    assert_eq!(decoded.as_int(), None);
+    assert_eq!(decoded.as_u64(), None);
+    assert_eq!(
+        decoded.as_decimal(),
+        Some("99999999999999999999999".to_string())
+    );
+
+    let (_, decoded) = decode(b"i18446744073709551615e").unwrap();
+    assert_eq!(decoded.as_int(), None);
+    assert_eq!(decoded.as_u64(), Some(u64::MAX));
+
+    let (_, decoded) = decode(b"i-5e").unwrap();
+    assert_eq!(decoded.as_int(), Some(-5));
+    assert_eq!(decoded.as_u64(), None);
+
+    // Non-canonical integers survive re-encoding
+    let input = b"d1:ai-0e1:bi007e1:ci-99999999999999999999ee";
+    let (_, decoded) = decode(input).unwrap();
+    assert_eq!(decoded.get_int("b"), Some(7));
+    assert_eq!(decoded.clone().into_owned().encode(), input);
+    assert_eq!(decoded.encode(), input);
+
+    test_decode!("i18446744073709551615e", 18446744073709551615u64);
+    test_decode!("i99999999999999999999999e", "99999999999999999999999");
+}
+
+#[test]
+fn reject_invalid_integers() {
+    assert!(decode(b"iabce").is_err());
+    assert!(decode(b"i-e").is_err());
+    assert!(decode(b"ie").is_err());
+    assert!(decode(b"i+5e").is_err());
+}
+
+#[test]
+fn reject_truncated_input() {
+    for input in [
+        &b""[..],
+        b"5:a",
+        b"-1:a",
+        b"5",
+        b"i5",
+        b"l",
+        b"li1e",
+        b"d",
+        b"d3:key",
+        b"d8:intervali900e5:peers99:x",
+    ] {
+        assert!(decode(input).is_err(), "{:?}", input);
+    }
+}
+
+#[test]
+fn look_up_keys_of_large_dictionaries() {
+    let mut input = b"d".to_vec();
+    for i in 0..50_000 {
+        let key = format!("{:08}", i);
+        input.extend(format!("{}:{}i{}e", key.len(), key, i).bytes());
+    }
+    input.push(b'e');
+    let (_, decoded) = decode(&input).unwrap();
+    assert_eq!(decoded.as_dict().unwrap().len(), 50_000);
+    assert_eq!(decoded.get_int("00031337"), Some(31337));
+    assert_eq!(decoded.encode(), input);
+}
+
+#[test]
+fn reject_duplicate_dictionary_keys() {
+    assert!(decode(b"d1:ai1e1:bi2e1:ai3ee").is_err());
+}
+
+#[test]
+fn reject_deeply_nested_input() {
+    let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+    assert!(decode(&nested(64)).is_ok());
+    assert!(decode(&nested(65)).is_err());
+    assert!(decode(&b"l".repeat(100_000)).is_err());
+    assert!(decode(&b"d1:a".repeat(100_000)).is_err());
+}
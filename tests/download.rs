@@ -0,0 +1,357 @@
+use std::fs;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::bitfield::Bitfield;
+use bittorrent_starter_rust::download::Download;
+use bittorrent_starter_rust::handshake::Handshake;
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_listener::PeerListener;
+use bittorrent_starter_rust::peer_message::PeerMessage;
+use bittorrent_starter_rust::tracker_server::TrackerConfig;
+
+mod common;
+use common::{
+    announced_listener, answer_handshake, spawn_http_tracker, spawn_tracker_server,
+    torrent_file_of, Seeder, PIECE_LENGTH,
+};
+
+#[test]
+fn download_every_piece_over_one_connection() {
+    let data: Vec<u8> = (0..3 * PIECE_LENGTH + 1000)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let torrent_file = torrent_file_of(spawn_tracker_server(TrackerConfig::default()), &data);
+    let seeder = Seeder::spawn(&torrent_file, &data, 0, Duration::ZERO);
+
+    let output = tempfile::NamedTempFile::new().unwrap();
+    Download::download_file(&torrent_file, &output.path().to_path_buf(), None).unwrap();
+    assert_eq!(fs::read(output.path()).unwrap(), data);
+    assert_eq!(seeder.connections.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn ban_peers_sending_corrupt_pieces() {
+    let data: Vec<u8> = (0..8 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+    let torrent_file = torrent_file_of(spawn_tracker_server(TrackerConfig::default()), &data);
+    // The corrupt seeder would get most pieces if it weren't banned
+    let honest = Seeder::spawn(&torrent_file, &data, 0, Duration::from_millis(50));
+    let corrupt = Seeder::spawn(&torrent_file, &data, usize::MAX, Duration::ZERO);
+
+    let output = tempfile::NamedTempFile::new().unwrap();
+    Download::download_file(&torrent_file, &output.path().to_path_buf(), None).unwrap();
+    assert_eq!(fs::read(output.path()).unwrap(), data);
+    assert_eq!(corrupt.connections.load(Ordering::SeqCst), 1);
+    // Two blocks for each of the four pieces that got it banned
+    assert_eq!(corrupt.blocks_sent.load(Ordering::SeqCst), 8);
+    assert_eq!(honest.blocks_sent.load(Ordering::SeqCst), 16);
+}
+
+#[test]
+fn keep_banned_peers_out_when_they_connect_back() {
+    let data: Vec<u8> = (0..8 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+    let torrent_file = torrent_file_of(spawn_tracker_server(TrackerConfig::default()), &data);
+    Seeder::spawn(&torrent_file, &data, 0, Duration::from_millis(50));
+    let corrupt = Seeder::spawn(&torrent_file, &data, usize::MAX, Duration::ZERO);
+    let listener = PeerListener::bind("127.0.0.1:0").unwrap();
+
+    // Once banned, the corrupt seeder connects to us from another port
+    let done = Arc::new(AtomicBool::new(false));
+    let comeback = {
+        let info_hash = torrent_file.info.hash_info().unwrap();
+        let listener_addr = listener.local_addr();
+        let peer_id = corrupt.peer_id;
+        let hung_up = corrupt.hung_up.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while hung_up.load(Ordering::SeqCst) == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let mut stream = TcpStream::connect(listener_addr).unwrap();
+            Handshake::new(info_hash, peer_id)
+                .exchange(&mut stream)
+                .unwrap();
+            PeerMessage::Bitfield(Bitfield::full(8).as_bytes().to_vec())
+                .write_to(&mut stream)
+                .unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            let mut received = vec![];
+            while !done.load(Ordering::SeqCst) {
+                if let Ok(message) = PeerMessage::read_from(&mut stream) {
+                    received.push(message);
+                }
+            }
+            received
+        })
+    };
+
+    let output = tempfile::NamedTempFile::new().unwrap();
+    Download::download_file(&torrent_file, &output.path().to_path_buf(), Some(&listener)).unwrap();
+    assert_eq!(fs::read(output.path()).unwrap(), data);
+    done.store(true, Ordering::SeqCst);
+    assert_eq!(corrupt.blocks_sent.load(Ordering::SeqCst), 8);
+    assert_eq!(comeback.join().unwrap(), vec![]);
+}
+
+#[test]
+fn retry_a_corrupt_piece_with_its_only_peer() {
+    let data: Vec<u8> = (0..3 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+    let torrent_file = torrent_file_of(spawn_tracker_server(TrackerConfig::default()), &data);
+    // Only the first piece it sends is corrupt
+    let seeder = Seeder::spawn(&torrent_file, &data, 2, Duration::ZERO);
+
+    let output = tempfile::NamedTempFile::new().unwrap();
+    Download::download_file(&torrent_file, &output.path().to_path_buf(), None).unwrap();
+    assert_eq!(fs::read(output.path()).unwrap(), data);
+    assert_eq!(seeder.connections.load(Ordering::SeqCst), 1);
+    assert_eq!(seeder.blocks_sent.load(Ordering::SeqCst), 8);
+}
+
+#[test]
+fn hand_no_pieces_to_inbound_peers_that_have_nothing() {
+    let data: Vec<u8> = (0..8 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+    let torrent_file = torrent_file_of(spawn_tracker_server(TrackerConfig::default()), &data);
+    Seeder::spawn(&torrent_file, &data, 0, Duration::from_millis(50));
+    let listener = PeerListener::bind("127.0.0.1:0").unwrap();
+
+    // A leecher connecting to us while we download, keeping quiet and us choked
+    let done = Arc::new(AtomicBool::new(false));
+    let leecher = {
+        let info_hash = torrent_file.info.hash_info().unwrap();
+        let listener_addr = listener.local_addr();
+        let done = done.clone();
+        thread::spawn(move || {
+            // The torrent is only known to the listener once the download started
+            let mut stream = loop {
+                assert!(!done.load(Ordering::SeqCst), "leecher didn't get in");
+                let mut stream = TcpStream::connect(listener_addr).unwrap();
+                if Handshake::new(info_hash, PeerId::generate())
+                    .exchange(&mut stream)
+                    .is_ok()
+                {
+                    break stream;
+                }
+                thread::sleep(Duration::from_millis(10));
+            };
+            stream
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            let mut received = vec![];
+            while !done.load(Ordering::SeqCst) {
+                if let Ok(message) = PeerMessage::read_from(&mut stream) {
+                    received.push(message);
+                }
+            }
+            received
+        })
+    };
+
+    let output = tempfile::NamedTempFile::new().unwrap();
+    Download::download_file(&torrent_file, &output.path().to_path_buf(), Some(&listener)).unwrap();
+    assert_eq!(fs::read(output.path()).unwrap(), data);
+    done.store(true, Ordering::SeqCst);
+    // Not even interested, since it got no piece
+    assert_eq!(leecher.join().unwrap(), vec![]);
+}
+
+#[test]
+fn give_idle_peers_the_pieces_they_get_later() {
+    let data: Vec<u8> = (0..2 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+    let torrent_file = torrent_file_of(spawn_tracker_server(TrackerConfig::default()), &data);
+    let info_hash = torrent_file.info.hash_info().unwrap();
+    let peer_id = PeerId::generate();
+    let listener = announced_listener(&torrent_file, peer_id);
+
+    // The only peer, having the first piece at first and the second one a while later
+    let seeder_data = data.clone();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        answer_handshake(&mut stream, info_hash, peer_id);
+        let mut bitfield = Bitfield::new(2);
+        bitfield.set(0).unwrap();
+        PeerMessage::Bitfield(bitfield.as_bytes().to_vec())
+            .write_to(&mut stream)
+            .unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let connected_at = Instant::now();
+        let mut told_have = false;
+        loop {
+            let answer = match PeerMessage::read_from(&mut stream) {
+                Ok(PeerMessage::Interested) => PeerMessage::Unchoke,
+                Ok(PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                }) => {
+                    let begin_in_data = index as usize * PIECE_LENGTH + begin as usize;
+                    PeerMessage::Piece {
+                        index,
+                        begin,
+                        block: seeder_data[begin_in_data..begin_in_data + length as usize].to_vec(),
+                    }
+                }
+                Ok(_) => continue,
+                Err(_) if !told_have && connected_at.elapsed() > Duration::from_millis(500) => {
+                    told_have = true;
+                    PeerMessage::Have(1)
+                }
+                Err(_) => continue,
+            };
+            if answer.write_to(&mut stream).is_err() {
+                return;
+            }
+        }
+    });
+
+    let output = tempfile::NamedTempFile::new().unwrap();
+    Download::download_file(&torrent_file, &output.path().to_path_buf(), None).unwrap();
+    assert_eq!(fs::read(output.path()).unwrap(), data);
+}
+
+#[test]
+fn hang_up_on_idle_peers_having_nothing_to_make_room() {
+    let data: Vec<u8> = (0..2 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+    // Re-announcing every second hands out the seeder once it's there
+    let torrent_file = torrent_file_of(
+        spawn_tracker_server(TrackerConfig {
+            interval: Duration::from_secs(1),
+            ..Default::default()
+        }),
+        &data,
+    );
+    let info_hash = torrent_file.info.hash_info().unwrap();
+
+    // As many peers having nothing as we'd connect to, taking every connection slot
+    let connections = Arc::new(AtomicUsize::new(0));
+    let hung_up = Arc::new(AtomicUsize::new(0));
+    for _ in 0..20 {
+        let peer_id = PeerId::generate();
+        let listener = announced_listener(&torrent_file, peer_id);
+        let connections = connections.clone();
+        let hung_up = hung_up.clone();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            connections.fetch_add(1, Ordering::SeqCst);
+            answer_handshake(&mut stream, info_hash, peer_id);
+            PeerMessage::Bitfield(Bitfield::new(2).as_bytes().to_vec())
+                .write_to(&mut stream)
+                .unwrap();
+            while PeerMessage::read_from(&mut stream).is_ok() {}
+            hung_up.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    let seeder = {
+        let torrent_file = torrent_file.clone();
+        let data = data.clone();
+        let connections = connections.clone();
+        thread::spawn(move || {
+            while connections.load(Ordering::SeqCst) < 20 {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Seeder::spawn(&torrent_file, &data, 0, Duration::ZERO)
+        })
+    };
+
+    let output = tempfile::NamedTempFile::new().unwrap();
+    Download::download_file(&torrent_file, &output.path().to_path_buf(), None).unwrap();
+    assert_eq!(fs::read(output.path()).unwrap(), data);
+    assert_eq!(seeder.join().unwrap().connections.load(Ordering::SeqCst), 1);
+    assert!(hung_up.load(Ordering::SeqCst) >= 1);
+}
+
+#[test]
+fn upload_pieces_to_peers_and_report_it() {
+    let data: Vec<u8> = (0..8 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+    // A second tracker handing out no peers records our announces
+    let (recording_url, heads) = spawn_http_tracker(b"d8:intervali1800e5:peers0:e");
+    let mut torrent_file = torrent_file_of(spawn_tracker_server(TrackerConfig::default()), &data);
+    torrent_file.announce_list = vec![vec![torrent_file.announce.clone()], vec![recording_url]];
+    Seeder::spawn(&torrent_file, &data, 0, Duration::from_millis(50));
+    let listener = PeerListener::bind("127.0.0.1:0").unwrap();
+
+    // A leecher asking us for a block of the first piece we tell it about
+    let leecher = {
+        let info_hash = torrent_file.info.hash_info().unwrap();
+        let listener_addr = listener.local_addr();
+        thread::spawn(move || {
+            // The torrent is only known to the listener once the download started
+            let mut stream = loop {
+                let mut stream = TcpStream::connect(listener_addr).unwrap();
+                if Handshake::new(info_hash, PeerId::generate())
+                    .exchange(&mut stream)
+                    .is_ok()
+                {
+                    break stream;
+                }
+                thread::sleep(Duration::from_millis(10));
+            };
+            for message in [
+                PeerMessage::Bitfield(Bitfield::new(8).as_bytes().to_vec()),
+                PeerMessage::Interested,
+            ] {
+                message.write_to(&mut stream).unwrap();
+            }
+            let (mut unchoked, mut have) = (false, None);
+            while !unchoked || have.is_none() {
+                match PeerMessage::read_from(&mut stream).unwrap() {
+                    PeerMessage::Unchoke => unchoked = true,
+                    PeerMessage::Have(index) => have = have.or(Some(index)),
+                    _ => {}
+                }
+            }
+            PeerMessage::Request {
+                index: have.unwrap(),
+                begin: 0,
+                length: 1 << 14,
+            }
+            .write_to(&mut stream)
+            .unwrap();
+            loop {
+                if let PeerMessage::Piece { index, block, .. } =
+                    PeerMessage::read_from(&mut stream).unwrap()
+                {
+                    break (index, block);
+                }
+            }
+        })
+    };
+
+    let output = tempfile::NamedTempFile::new().unwrap();
+    Download::download_file(&torrent_file, &output.path().to_path_buf(), Some(&listener)).unwrap();
+    assert_eq!(fs::read(output.path()).unwrap(), data);
+    let (index, block) = leecher.join().unwrap();
+    let begin_in_data = index as usize * PIECE_LENGTH;
+    assert_eq!(block, data[begin_in_data..begin_in_data + (1 << 14)]);
+
+    let request_lines: Vec<String> = heads.try_iter().map(|head| head[0].clone()).collect();
+    let completed = request_lines
+        .iter()
+        .find(|request_line| request_line.contains("&event=completed"))
+        .unwrap();
+    assert!(completed.contains("&uploaded=16384&"));
+}
+
+#[test]
+fn announce_no_usable_port_without_a_listener() {
+    let data: Vec<u8> = (0..PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+    let (recording_url, heads) = spawn_http_tracker(b"d8:intervali1800e5:peers0:e");
+    let mut torrent_file = torrent_file_of(spawn_tracker_server(TrackerConfig::default()), &data);
+    torrent_file.announce_list = vec![vec![torrent_file.announce.clone()], vec![recording_url]];
+    Seeder::spawn(&torrent_file, &data, 0, Duration::ZERO);
+
+    let output = tempfile::NamedTempFile::new().unwrap();
+    Download::download_file(&torrent_file, &output.path().to_path_buf(), None).unwrap();
+    assert_eq!(fs::read(output.path()).unwrap(), data);
+    let request_lines: Vec<String> = heads.try_iter().map(|head| head[0].clone()).collect();
+    assert!(!request_lines.is_empty());
+    for request_line in request_lines {
+        assert!(request_line.contains("&port=0&"));
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::{self, Cursor, Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use bittorrent_starter_rust::handshake::{Capabilities, Handshake, HandshakeError};
+use bittorrent_starter_rust::peer::Peer;
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::torrent_file::{TorrentFile, TorrentFileInfo};
+
+// A peer that answers with canned bytes and keeps what we send it
+struct FakePeer {
+    answer: Cursor<Vec<u8>>,
+    received: Vec<u8>,
+}
+
+impl FakePeer {
+    fn answering(answer: &[u8]) -> Self {
+        Self {
+            answer: Cursor::new(answer.to_vec()),
+            received: vec![],
+        }
+    }
+}
+
+impl Read for FakePeer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.answer.read(buf)
+    }
+}
+
+impl Write for FakePeer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.received.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn map_capabilities_to_reserved_bits() {
+    assert_eq!(
+        Capabilities::EXTENSION_PROTOCOL.to_reserved_bytes(),
+        [0, 0, 0, 0, 0, 0x10, 0, 0]
+    );
+    assert_eq!(
+        (Capabilities::DHT | Capabilities::FAST | Capabilities::V2).to_reserved_bytes(),
+        [0, 0, 0, 0, 0, 0, 0, 0x15]
+    );
+    assert_eq!(
+        Capabilities::from_reserved_bytes([0, 0, 0, 0, 0, 0x10, 0, 0x01]),
+        Capabilities::EXTENSION_PROTOCOL | Capabilities::DHT
+    );
+}
+
+#[test]
+fn negotiate_what_both_sides_support() {
+    let ours = Capabilities::EXTENSION_PROTOCOL | Capabilities::FAST;
+    let theirs = Capabilities::from_reserved_bytes([0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+
+    let negotiated = ours.negotiate(theirs);
+    assert!(negotiated.contains(Capabilities::EXTENSION_PROTOCOL));
+    assert!(!negotiated.contains(Capabilities::FAST));
+    assert!(!negotiated.contains(Capabilities::DHT));
+    assert_eq!(Capabilities::NONE.negotiate(theirs), Capabilities::NONE);
+}
+
+#[test]
+fn advertise_our_capabilities() {
+    let handshake = Handshake::new([1; 20], PeerId::generate());
+    assert_eq!(handshake.capabilities(), Capabilities::ours());
+    assert!(handshake
+        .capabilities()
+        .contains(Capabilities::EXTENSION_PROTOCOL));
+}
+
+#[test]
+fn encode_and_parse_handshakes() {
+    let handshake = Handshake::new([1; 20], PeerId::from(*b"-AA0001-aaaaaaaaaaaa"));
+    let bytes = handshake.to_bytes();
+    assert_eq!(bytes[0], 19);
+    assert_eq!(&bytes[1..20], b"BitTorrent protocol");
+    assert_eq!(&bytes[48..], b"-AA0001-aaaaaaaaaaaa");
+    assert_eq!(Handshake::parse(&bytes).unwrap(), handshake);
+}
+
+#[test]
+fn reject_malformed_handshakes() {
+    let bytes = Handshake::new([1; 20], PeerId::generate()).to_bytes();
+    assert_eq!(
+        Handshake::parse(&bytes[..40]),
+        Err(HandshakeError::Truncated(40))
+    );
+
+    let mut other_protocol = bytes;
+    other_protocol[1..20].copy_from_slice(b"BitTorrent protocoX");
+    assert_eq!(
+        Handshake::parse(&other_protocol),
+        Err(HandshakeError::WrongProtocol(
+            "BitTorrent protocoX".to_string()
+        ))
+    );
+}
+
+#[test]
+fn exchange_handshakes_for_the_same_torrent() {
+    let ours = Handshake::new([1; 20], PeerId::generate());
+    let theirs = Handshake::new([1; 20], PeerId::from(*b"-AA0001-aaaaaaaaaaaa"));
+    let mut peer = FakePeer::answering(&theirs.to_bytes());
+    assert_eq!(ours.exchange(&mut peer).unwrap(), theirs);
+    assert_eq!(peer.received, ours.to_bytes());
+
+    let other_torrent = Handshake::new([2; 20], PeerId::generate());
+    let error = ours
+        .exchange(&mut FakePeer::answering(&other_torrent.to_bytes()))
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<HandshakeError>(),
+        Some(&HandshakeError::InfoHashMismatch {
+            expected: [1; 20],
+            got: [2; 20]
+        })
+    );
+
+    let error = ours
+        .exchange(&mut FakePeer::answering(&theirs.to_bytes()[..10]))
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<HandshakeError>(),
+        Some(&HandshakeError::Truncated(10))
+    );
+}
+
+#[test]
+fn fail_to_connect_to_misbehaving_peers() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer_addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.read_exact(&mut [0; 68]).unwrap();
+        stream.write_all(&[b'x'; 68]).unwrap();
+    });
+
+    let torrent_file = TorrentFile {
+        announce: "http://127.0.0.1/announce".to_string(),
+        announce_list: vec![],
+        info: TorrentFileInfo {
+            name: "sample.txt".to_string(),
+            piece_length: 32768,
+            pieces: vec![0; 20],
+            length: 100,
+            files: None,
+            info_hash: None,
+        },
+    };
+    let error = Peer::new(peer_addr, torrent_file).err().unwrap();
+    assert!(error.downcast_ref::<HandshakeError>().is_some());
+}
@@ -0,0 +1,96 @@
+use std::sync::Once;
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::http_client::HttpConfig;
+use bittorrent_starter_rust::tracker::{announce, AnnounceParams};
+
+mod common;
+use common::spawn_http_server;
+
+// The configuration is shared by the whole process, so every test gets the same one
+fn configure() {
+    static CONFIGURE: Once = Once::new();
+    CONFIGURE.call_once(|| {
+        HttpConfig {
+            user_agent: "test-agent/1".to_string(),
+            read_timeout: Duration::from_millis(300),
+            ..Default::default()
+        }
+        .set()
+        .unwrap()
+    });
+}
+
+#[test]
+fn send_our_user_agent() {
+    configure();
+    let (addr, heads) = spawn_http_server(
+        "HTTP/1.1 200 OK\r\nContent-Length: 15\r\nConnection: close\r\n\r\nd8:intervali1ee",
+    );
+
+    announce(
+        &format!("http://{}/announce", addr),
+        &AnnounceParams::new([1; 20], 1),
+    )
+    .unwrap();
+    assert!(heads
+        .recv()
+        .unwrap()
+        .iter()
+        .any(|line| line.eq_ignore_ascii_case("user-agent: test-agent/1")));
+}
+
+#[test]
+fn give_up_on_slow_trackers() {
+    configure();
+    let (addr, _heads) = spawn_http_server("");
+
+    let started = Instant::now();
+    assert!(announce(
+        &format!("http://{}/announce", addr),
+        &AnnounceParams::new([1; 20], 1)
+    )
+    .is_err());
+    assert!(started.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+fn limit_redirects() {
+    let (addr, heads) = spawn_http_server(
+        "HTTP/1.1 302 Found\r\nLocation: /next\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+    );
+    let client = HttpConfig {
+        max_redirects: 0,
+        ..Default::default()
+    }
+    .build_blocking()
+    .unwrap();
+
+    assert!(client
+        .get(format!("http://{}/announce", addr))
+        .send()
+        .is_err());
+    assert_eq!(heads.try_iter().count(), 1);
+}
+
+#[test]
+fn go_through_the_proxy() {
+    let (proxy_addr, heads) = spawn_http_server(
+        "HTTP/1.1 200 OK\r\nContent-Length: 15\r\nConnection: close\r\n\r\nd8:intervali1ee",
+    );
+    let client = HttpConfig {
+        proxy: Some(format!("http://{}", proxy_addr)),
+        ..Default::default()
+    }
+    .build_blocking()
+    .unwrap();
+
+    client
+        .get("http://tracker.invalid/announce")
+        .send()
+        .unwrap();
+    assert_eq!(
+        heads.recv().unwrap()[0],
+        "GET http://tracker.invalid/announce HTTP/1.1"
+    );
+}
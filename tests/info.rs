@@ -1,4 +1,7 @@
-use bittorrent_starter_rust::torrent_file::{parse_torrent_file, TorrentFile, TorrentFileInfo};
+use bittorrent_starter_rust::torrent_file::{
+    parse_info_dictionary, parse_torrent_file, TorrentFile, TorrentFileInfo,
+};
+use sha1::{Digest, Sha1};
 
 #[test]
 fn parse_the_torrent_file() {
@@ -21,6 +24,7 @@ fn parse_the_torrent_file() {
         .unwrap(),
         TorrentFile {
             announce: "http://bittorrent-test-tracker.codecrafters.io/announce".to_string(),
+            announce_list: vec![],
             info: TorrentFileInfo {
                 name: "sample.txt".to_string(),
                 piece_length: 32768,
@@ -30,12 +34,33 @@ fn parse_the_torrent_file() {
                     181, 82, 4, 173, 141, 53, 240, 13, 147, 122, 2, 19, 223, 25, 130, 188, 141, 9,
                     114, 39, 173, 158, 144, 154, 204, 23
                 ],
-                length: 92063
+                length: 92063,
+                files: None,
+                info_hash: Some([
+                    214, 159, 145, 230, 178, 174, 76, 84, 36, 104, 209, 7, 58, 113, 212, 234, 19,
+                    135, 154, 127
+                ]),
             }
         }
     );
 }
 
+#[test]
+fn hash_info_keys_that_arent_kept() {
+    let info =
+        b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee";
+    let torrent_file =
+        parse_torrent_file(&[&b"d8:announce3:url4:info"[..], info, b"e"].concat()).unwrap();
+    assert_eq!(
+        torrent_file.info.hash_info().unwrap(),
+        <[u8; 20]>::from(Sha1::digest(info))
+    );
+    assert_eq!(
+        parse_info_dictionary(info).unwrap().hash_info().unwrap(),
+        <[u8; 20]>::from(Sha1::digest(info))
+    );
+}
+
 #[test]
 fn hash_the_torrent_file_info() {
     assert_eq!(
@@ -48,7 +73,9 @@ fn hash_the_torrent_file_info() {
                 173, 141, 53, 240, 13, 147, 122, 2, 19, 223, 25, 130, 188, 141, 9, 114, 39, 173,
                 158, 144, 154, 204, 23
             ],
-            length: 92063
+            length: 92063,
+            files: None,
+            info_hash: None,
         }
         .hash_info()
         .unwrap(),
@@ -71,7 +98,9 @@ fn hex_the_torrent_file_info() {
                 173, 141, 53, 240, 13, 147, 122, 2, 19, 223, 25, 130, 188, 141, 9, 114, 39, 173,
                 158, 144, 154, 204, 23
             ],
-            length: 92063
+            length: 92063,
+            files: None,
+            info_hash: None,
         }
         .hex_info()
         .unwrap(),
@@ -91,7 +120,9 @@ fn url_encode_the_torrent_file_info() {
                 173, 141, 53, 240, 13, 147, 122, 2, 19, 223, 25, 130, 188, 141, 9, 114, 39, 173,
                 158, 144, 154, 204, 23
             ],
-            length: 92063
+            length: 92063,
+            files: None,
+            info_hash: None,
         }
         .url_encoded_hash_info()
         .unwrap(),
@@ -111,7 +142,9 @@ fn hax_the_torrent_file_pieces() {
                 173, 141, 53, 240, 13, 147, 122, 2, 19, 223, 25, 130, 188, 141, 9, 114, 39, 173,
                 158, 144, 154, 204, 23
             ],
-            length: 92063
+            length: 92063,
+            files: None,
+            info_hash: None,
         }
         .hex_pieces()
         .unwrap(),
@@ -122,3 +155,38 @@ fn hax_the_torrent_file_pieces() {
         ]
     )
 }
+
+#[test]
+fn list_the_trackers_of_every_tier() {
+    let info = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let torrent_file = parse_torrent_file(
+        &[
+            &b"d8:announce1:a13:announce-listll1:a1:bel1:cel1:bi5eelee4:info"[..],
+            info,
+            b"e",
+        ]
+        .concat(),
+    )
+    .unwrap();
+    assert_eq!(torrent_file.announce, "a");
+    assert_eq!(
+        torrent_file.announce_list,
+        vec![
+            vec!["a".to_string(), "b".to_string()],
+            vec!["c".to_string()],
+            vec!["b".to_string()]
+        ]
+    );
+    assert_eq!(torrent_file.trackers(), vec!["a", "b", "c"]);
+
+    // The first tracker stands in for a missing announce, and an empty tier is dropped
+    let torrent_file =
+        parse_torrent_file(&[&b"d13:announce-listllel1:bee4:info"[..], info, b"e"].concat())
+            .unwrap();
+    assert_eq!(torrent_file.announce, "b");
+    assert_eq!(torrent_file.trackers(), vec!["b"]);
+
+    let torrent_file =
+        parse_torrent_file(&[&b"d8:announce1:a4:info"[..], info, b"e"].concat()).unwrap();
+    assert_eq!(torrent_file.trackers(), vec!["a"]);
+}
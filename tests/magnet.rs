@@ -0,0 +1,105 @@
+use std::net::TcpListener;
+use std::thread;
+
+use bittorrent_starter_rust::handshake::Handshake;
+use bittorrent_starter_rust::magnet::MagnetLink;
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_message::PeerMessage;
+use sha1::{Digest, Sha1};
+
+mod common;
+use common::spawn_http_tracker;
+
+const INFO_HASH: [u8; 20] = [
+    214, 159, 145, 230, 178, 174, 76, 84, 36, 104, 209, 7, 58, 113, 212, 234, 19, 135, 154, 127,
+];
+
+#[test]
+fn parse_a_hex_magnet_link() {
+    assert_eq!(
+        MagnetLink::parse(
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample.txt&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce"
+        )
+        .unwrap(),
+        MagnetLink {
+            info_hash: INFO_HASH,
+            display_name: Some("sample.txt".to_string()),
+            trackers: vec!["http://bittorrent-test-tracker.codecrafters.io/announce".to_string()],
+        }
+    )
+}
+
+#[test]
+fn parse_a_base32_magnet_link() {
+    assert_eq!(
+        MagnetLink::parse("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7")
+            .unwrap()
+            .info_hash,
+        INFO_HASH
+    )
+}
+
+#[test]
+fn reject_a_magnet_link_without_info_hash() {
+    assert!(MagnetLink::parse("magnet:?dn=sample.txt").is_err());
+    assert!(MagnetLink::parse("https://example.com/?xt=urn:btih:x").is_err());
+}
+
+#[test]
+fn fetch_the_metadata_and_keep_every_tracker() {
+    let info = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let info_hash: [u8; 20] = Sha1::digest(info).into();
+
+    // A peer handing out the metadata in a single piece over ut_metadata
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        Handshake::new(info_hash, PeerId(*b"-XX0001-xxxxxxxxxxxx"))
+            .exchange(&mut stream)
+            .unwrap();
+        let Ok(PeerMessage::Extended { payload, .. }) = PeerMessage::read_from(&mut stream) else {
+            panic!("expected an extension handshake");
+        };
+        assert_eq!(payload, b"d1:md11:ut_metadatai1eee");
+        PeerMessage::Extended {
+            id: 0,
+            payload: format!("d1:md11:ut_metadatai3ee13:metadata_sizei{}ee", info.len())
+                .into_bytes(),
+        }
+        .write_to(&mut stream)
+        .unwrap();
+        let Ok(PeerMessage::Extended { id: 3, .. }) = PeerMessage::read_from(&mut stream) else {
+            panic!("expected a metadata request");
+        };
+        let mut payload =
+            format!("d8:msg_typei1e5:piecei0e10:total_sizei{}ee", info.len()).into_bytes();
+        payload.extend(info);
+        PeerMessage::Extended { id: 1, payload }
+            .write_to(&mut stream)
+            .unwrap();
+    });
+
+    let mut peers = b"d8:intervali900e5:peers6:\x7f\x00\x00\x01".to_vec();
+    peers.extend(port.to_be_bytes());
+    peers.push(b'e');
+    let (announce_url, heads) = spawn_http_tracker(&peers);
+    // Refuses connections, so it never answers
+    let dead_announce_url = "http://127.0.0.1:1/announce".to_string();
+
+    let torrent_file = MagnetLink {
+        info_hash,
+        display_name: None,
+        trackers: vec![dead_announce_url.clone(), announce_url.clone()],
+    }
+    .fetch_torrent_file()
+    .unwrap();
+    assert_eq!(torrent_file.info.hash_info().unwrap(), info_hash);
+    assert_eq!(torrent_file.announce, dead_announce_url);
+    assert_eq!(
+        torrent_file.trackers(),
+        vec![dead_announce_url.clone(), announce_url.clone()]
+    );
+    // Nothing made up about how much is left before knowing the size
+    assert!(heads.recv().unwrap()[0].contains("&left=0&"));
+}
@@ -0,0 +1,392 @@
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use bittorrent_starter_rust::peer::{Peer, PeerError, PeerState, PipelineConfig};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_message::PeerMessage;
+use bittorrent_starter_rust::torrent_file::TorrentFile;
+
+mod common;
+use common::{answer_handshake, torrent_file_of};
+
+const BLOCK_SIZE: u32 = 1 << 14;
+
+// Two pieces, the first one made of two blocks, and the second one starting over from 0
+fn sample_torrent_file() -> TorrentFile {
+    torrent_file_of("http://127.0.0.1/announce", &block(0, 2 * BLOCK_SIZE + 100))
+}
+
+fn block(begin: u32, length: u32) -> Vec<u8> {
+    (begin..begin + length).map(|i| i as u8).collect()
+}
+
+// A seeder that answers our handshake, then plays its part of the conversation
+fn spawn_seeder(
+    torrent_file: &TorrentFile,
+    script: impl FnOnce(&mut TcpStream) + Send + 'static,
+) -> (SocketAddr, JoinHandle<()>) {
+    let info_hash = torrent_file.info.hash_info().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer_addr = listener.local_addr().unwrap();
+    let seeder = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        answer_handshake(&mut stream, info_hash, PeerId::generate());
+        script(&mut stream);
+    });
+    (peer_addr, seeder)
+}
+
+fn expect(stream: &mut TcpStream, message: PeerMessage) {
+    assert_eq!(PeerMessage::read_from(stream).unwrap(), message);
+}
+
+fn send(stream: &mut TcpStream, message: PeerMessage) {
+    message.write_to(stream).unwrap();
+}
+
+// One request at a time, for seeders expecting them in order
+fn connect_unpipelined(peer_addr: SocketAddr, torrent_file: TorrentFile) -> Peer {
+    let mut peer = Peer::new(peer_addr, torrent_file).unwrap();
+    peer.set_pipeline(PipelineConfig {
+        min_requests: 1,
+        max_requests: 1,
+        ..Default::default()
+    });
+    peer
+}
+
+fn request(begin: u32) -> PeerMessage {
+    PeerMessage::Request {
+        index: 0,
+        begin,
+        length: BLOCK_SIZE,
+    }
+}
+
+fn piece(begin: u32) -> PeerMessage {
+    PeerMessage::Piece {
+        index: 0,
+        begin,
+        block: block(begin, BLOCK_SIZE),
+    }
+}
+
+#[test]
+fn download_whatever_order_messages_come_in() {
+    let torrent_file = sample_torrent_file();
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        // No bitfield, a have before the unchoke, and a choke dropping the first request
+        send(stream, PeerMessage::Have(0));
+        expect(stream, PeerMessage::Interested);
+        send(stream, PeerMessage::Interested);
+        send(stream, PeerMessage::Unchoke);
+        expect(stream, request(0));
+        send(stream, PeerMessage::Choke);
+        send(stream, PeerMessage::KeepAlive);
+        send(stream, PeerMessage::Unchoke);
+        expect(stream, request(0));
+        send(stream, piece(0));
+        expect(stream, request(BLOCK_SIZE));
+        send(
+            stream,
+            PeerMessage::Unknown {
+                id: 13,
+                payload: vec![],
+            },
+        );
+        send(stream, piece(BLOCK_SIZE));
+    });
+
+    let mut peer = connect_unpipelined(peer_addr, torrent_file);
+    assert_eq!(peer.state(), PeerState::default());
+    let piece = peer.download_a_piece(0).unwrap();
+    assert_eq!(piece, block(0, 2 * BLOCK_SIZE));
+    assert_eq!(
+        peer.state(),
+        PeerState {
+            am_choking: true,
+            am_interested: true,
+            peer_choking: false,
+            peer_interested: true,
+        }
+    );
+    seeder.join().unwrap();
+}
+
+#[test]
+fn fail_when_the_peer_hangs_up_while_choking() {
+    let torrent_file = sample_torrent_file();
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        send(stream, PeerMessage::Bitfield(vec![0b1100_0000]));
+        expect(stream, PeerMessage::Interested);
+    });
+
+    let mut peer = Peer::new(peer_addr, torrent_file).unwrap();
+    assert!(peer.download_a_piece(0).is_err());
+    assert!(peer.state().peer_choking);
+    seeder.join().unwrap();
+}
+
+#[test]
+fn download_successive_pieces_over_one_connection() {
+    let torrent_file = sample_torrent_file();
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        send(stream, PeerMessage::Bitfield(vec![0b1000_0000]));
+        expect(stream, PeerMessage::Interested);
+        send(stream, PeerMessage::Unchoke);
+        expect(stream, request(0));
+        send(stream, piece(0));
+        expect(stream, request(BLOCK_SIZE));
+        send(stream, PeerMessage::Have(1));
+        send(stream, piece(BLOCK_SIZE));
+
+        // Neither another bitfield nor another unchoke for the next piece
+        expect(
+            stream,
+            PeerMessage::Request {
+                index: 1,
+                begin: 0,
+                length: 100,
+            },
+        );
+        send(
+            stream,
+            PeerMessage::Piece {
+                index: 1,
+                begin: 0,
+                block: block(0, 100),
+            },
+        );
+    });
+
+    let mut peer = connect_unpipelined(peer_addr, torrent_file);
+    assert_eq!(peer.download_a_piece(0).unwrap(), block(0, 2 * BLOCK_SIZE));
+    assert_eq!(peer.bitfield().as_bytes(), [0b1100_0000]);
+    assert_eq!(peer.download_a_piece(1).unwrap(), block(0, 100));
+    seeder.join().unwrap();
+}
+
+#[test]
+fn keep_several_requests_outstanding() {
+    let torrent_file = sample_torrent_file();
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        send(stream, PeerMessage::Bitfield(vec![0b1100_0000]));
+        expect(stream, PeerMessage::Interested);
+        send(stream, PeerMessage::Unchoke);
+        expect(stream, request(0));
+        expect(stream, request(BLOCK_SIZE));
+        // Blocks come back in any order
+        send(stream, piece(BLOCK_SIZE));
+        send(stream, piece(0));
+    });
+
+    let mut peer = Peer::new(peer_addr, torrent_file).unwrap();
+    peer.set_pipeline(PipelineConfig {
+        min_requests: 2,
+        max_requests: 8,
+        ..Default::default()
+    });
+    assert_eq!(peer.queue_depth(), 2);
+    assert_eq!(peer.download_a_piece(0).unwrap(), block(0, 2 * BLOCK_SIZE));
+    // A local seeder is as fast as it gets
+    assert_eq!(peer.queue_depth(), 8);
+    seeder.join().unwrap();
+}
+
+#[test]
+fn cancel_requests_the_peer_doesnt_answer() {
+    let torrent_file = sample_torrent_file();
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        send(stream, PeerMessage::Bitfield(vec![0b1100_0000]));
+        expect(stream, PeerMessage::Interested);
+        send(stream, PeerMessage::Unchoke);
+        expect(stream, request(0));
+        expect(stream, request(BLOCK_SIZE));
+        send(stream, piece(0));
+        expect(
+            stream,
+            PeerMessage::Cancel {
+                index: 0,
+                begin: BLOCK_SIZE,
+                length: BLOCK_SIZE,
+            },
+        );
+    });
+
+    let mut peer = Peer::new(peer_addr, torrent_file).unwrap();
+    peer.set_pipeline(PipelineConfig {
+        min_requests: 2,
+        max_requests: 2,
+        request_timeout: Duration::from_millis(200),
+    });
+    let error = peer.download_a_piece(0).unwrap_err();
+    assert!(error.to_string().contains("didn't answer 1 requests"));
+    seeder.join().unwrap();
+}
+
+#[test]
+fn only_ask_for_pieces_the_peer_has() {
+    let torrent_file = sample_torrent_file();
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        send(stream, PeerMessage::Bitfield(vec![0b0100_0000]));
+        expect(stream, PeerMessage::Interested);
+        send(stream, PeerMessage::Unchoke);
+        // Nothing is asked for the first piece
+        expect(
+            stream,
+            PeerMessage::Request {
+                index: 1,
+                begin: 0,
+                length: 100,
+            },
+        );
+        send(
+            stream,
+            PeerMessage::Piece {
+                index: 1,
+                begin: 0,
+                block: block(0, 100),
+            },
+        );
+        // Have for a piece past the last one
+        send(stream, PeerMessage::Have(2));
+    });
+
+    let mut peer = Peer::new(peer_addr, torrent_file).unwrap();
+    let error = peer.download_a_piece(0).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<PeerError>(),
+        Some(&PeerError::PieceUnavailable(0))
+    );
+    assert_eq!(peer.download_a_piece(1).unwrap(), block(0, 100));
+    assert!(peer.receive().is_err());
+    seeder.join().unwrap();
+}
+
+#[test]
+fn reject_pieces_not_matching_their_hash() {
+    let torrent_file = sample_torrent_file();
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        send(stream, PeerMessage::Bitfield(vec![0b1100_0000]));
+        expect(stream, PeerMessage::Interested);
+        send(stream, PeerMessage::Unchoke);
+        expect(
+            stream,
+            PeerMessage::Request {
+                index: 1,
+                begin: 0,
+                length: 100,
+            },
+        );
+        send(
+            stream,
+            PeerMessage::Piece {
+                index: 1,
+                begin: 0,
+                block: vec![0; 100],
+            },
+        );
+        // The connection is still good for the next piece
+        expect(
+            stream,
+            PeerMessage::Request {
+                index: 1,
+                begin: 0,
+                length: 100,
+            },
+        );
+        send(
+            stream,
+            PeerMessage::Piece {
+                index: 1,
+                begin: 0,
+                block: block(0, 100),
+            },
+        );
+    });
+
+    let mut peer = Peer::new(peer_addr, torrent_file).unwrap();
+    let error = peer.download_a_piece(1).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<PeerError>(),
+        Some(&PeerError::HashMismatch(1))
+    );
+    assert_eq!(peer.download_a_piece(1).unwrap(), block(0, 100));
+    seeder.join().unwrap();
+}
+
+#[test]
+fn leave_the_piece_to_others_when_kept_choked() {
+    let torrent_file = sample_torrent_file();
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        send(stream, PeerMessage::Bitfield(vec![0b1100_0000]));
+        expect(stream, PeerMessage::Interested);
+        // Never unchoke, until we're hung up on
+        while PeerMessage::read_from(stream).is_ok() {}
+    });
+
+    let mut peer = Peer::new(peer_addr, torrent_file).unwrap();
+    peer.set_pipeline(PipelineConfig {
+        request_timeout: Duration::from_millis(200),
+        ..Default::default()
+    });
+    let error = peer.download_a_piece(0).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<PeerError>(),
+        Some(&PeerError::PieceUnavailable(0))
+    );
+    drop(peer);
+    seeder.join().unwrap();
+}
+
+#[test]
+fn hang_up_when_a_message_stops_halfway() {
+    let torrent_file = sample_torrent_file();
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        send(stream, PeerMessage::Bitfield(vec![0b1100_0000]));
+        expect(stream, PeerMessage::Interested);
+        // Part of a length prefix, then nothing
+        stream.write_all(&[0, 0]).unwrap();
+        while PeerMessage::read_from(stream).is_ok() {}
+    });
+
+    let mut peer = Peer::new(peer_addr, torrent_file).unwrap();
+    peer.set_pipeline(PipelineConfig {
+        request_timeout: Duration::from_millis(200),
+        ..Default::default()
+    });
+    let error = peer.download_a_piece(0).unwrap_err();
+    // The connection is out of step, not waiting for another piece
+    assert_eq!(error.downcast_ref::<PeerError>(), None);
+    drop(peer);
+    seeder.join().unwrap();
+}
+
+#[test]
+fn wait_for_the_peer_to_tell_what_it_has() {
+    let torrent_file = sample_torrent_file();
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        send(stream, PeerMessage::KeepAlive);
+        send(stream, PeerMessage::Bitfield(vec![0b0100_0000]));
+    });
+
+    let mut peer = Peer::new(peer_addr, torrent_file.clone()).unwrap();
+    peer.wait_for_availability().unwrap();
+    assert_eq!(peer.bitfield().as_bytes(), [0b0100_0000]);
+    seeder.join().unwrap();
+
+    // A peer keeping quiet isn't waited for forever
+    let (peer_addr, seeder) = spawn_seeder(&torrent_file, |stream| {
+        while PeerMessage::read_from(stream).is_ok() {}
+    });
+    let mut peer = Peer::new(peer_addr, torrent_file).unwrap();
+    peer.set_pipeline(PipelineConfig {
+        request_timeout: Duration::from_millis(200),
+        ..Default::default()
+    });
+    assert!(peer.wait_for_availability().is_err());
+    drop(peer);
+    seeder.join().unwrap();
+}
@@ -0,0 +1,97 @@
+use bittorrent_starter_rust::peer_id::PeerId;
+
+#[test]
+fn generate_azureus_style_peer_ids() {
+    let peer_id = PeerId::generate();
+    assert!(peer_id.as_bytes().starts_with(b"-BS0001-"));
+    assert!(peer_id
+        .as_bytes()
+        .iter()
+        .all(|byte| byte.is_ascii_alphanumeric() || *byte == b'-'));
+    assert_ne!(peer_id, PeerId::generate());
+}
+
+#[test]
+fn parse_peer_ids_and_prefixes() {
+    let peer_id: PeerId = "-qB4630-abcdefghijkl".parse().unwrap();
+    assert_eq!(peer_id.as_bytes(), b"-qB4630-abcdefghijkl");
+    assert_eq!(peer_id.to_string(), "-qB4630-abcdefghijkl");
+
+    let peer_id: PeerId = "-XX0001-".parse().unwrap();
+    assert!(peer_id.as_bytes().starts_with(b"-XX0001-"));
+
+    assert!("-XX0001-abcdefghijklm".parse::<PeerId>().is_err());
+}
+
+#[test]
+fn show_unprintable_peer_ids_in_hex() {
+    assert_eq!(
+        PeerId::from([0xff; 20]).to_string(),
+        "ffffffffffffffffffffffffffffffffffffffff"
+    );
+}
+
+#[test]
+fn keep_one_peer_id_per_session() {
+    let peer_id: PeerId = "-ZZ0001-".parse().unwrap();
+    PeerId::set_session(peer_id).unwrap();
+    assert_eq!(PeerId::session(), peer_id);
+    assert!(PeerId::set_session(PeerId::generate()).is_err());
+}
+
+#[test]
+fn identify_clients_from_peer_ids() {
+    let client = |id: &[u8; 20]| PeerId::from(*id).client().map(|client| client.to_string());
+
+    // Azureus style, with unknown codes named by the code
+    assert_eq!(
+        client(b"-qB4630-abcdefghijkl").as_deref(),
+        Some("qBittorrent 4.6.3")
+    );
+    assert_eq!(
+        client(b"-TR4000-abcdefghijkl").as_deref(),
+        Some("Transmission 4.0")
+    );
+    assert_eq!(
+        client(b"-BS0001-abcdefghijkl").as_deref(),
+        Some("BS 0.0.0.1")
+    );
+
+    // Shadow and Mainline style
+    assert_eq!(
+        client(b"S58B-----abcdefghijk").as_deref(),
+        Some("Shadow 5.8.11")
+    );
+    assert_eq!(
+        client(b"T03I-----abcdefghijk").as_deref(),
+        Some("BitTornado 0.3.18")
+    );
+    assert_eq!(
+        client(b"M7-10-3--abcdefghijk").as_deref(),
+        Some("BitTorrent 7.10.3")
+    );
+
+    // Special cases
+    assert_eq!(
+        client(b"exbc\x00\x3aLORDabcdefghij").as_deref(),
+        Some("BitLord 0.58")
+    );
+    assert_eq!(
+        client(b"-ML2.7.2-abcdefghijk").as_deref(),
+        Some("MLDonkey 2.7.2")
+    );
+    assert_eq!(
+        client(b"XBT054d-abcdefghijkl").as_deref(),
+        Some("XBT Client 0.5.4")
+    );
+
+    assert_eq!(client(&[0xff; 20]), None);
+    assert_eq!(client(b"abcdefghijklmnopqrst"), None);
+    assert_eq!(
+        PeerId::from(*b"-UT355S-abcdefghijkl")
+            .client()
+            .unwrap()
+            .name,
+        "µTorrent"
+    );
+}
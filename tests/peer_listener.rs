@@ -0,0 +1,80 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+use std::time::Duration;
+
+use bittorrent_starter_rust::handshake::{Capabilities, Handshake, HandshakeError};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_listener::PeerListener;
+use bittorrent_starter_rust::torrent_file::TorrentFile;
+
+mod common;
+use common::torrent_file_of;
+
+fn sample_torrent_file() -> TorrentFile {
+    torrent_file_of("http://127.0.0.1/announce", &[0; 100])
+}
+
+#[test]
+fn hand_inbound_peers_to_their_torrent() {
+    let listener = PeerListener::bind("127.0.0.1:0").unwrap();
+    let torrent_file = sample_torrent_file();
+    let info_hash = torrent_file.info.hash_info().unwrap();
+    let inbound_peers = listener.register(&torrent_file).unwrap();
+    assert!(listener.register(&torrent_file).is_err());
+
+    let mut stream = TcpStream::connect(listener.local_addr()).unwrap();
+    let ours = Handshake::new(info_hash, PeerId::from(*b"-AA0001-aaaaaaaaaaaa"));
+    let theirs = ours.exchange(&mut stream).unwrap();
+    assert_eq!(theirs.peer_id, PeerId::session().0);
+
+    let (peer_addr, peer) = inbound_peers.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(peer_addr, stream.local_addr().unwrap());
+    assert!(peer
+        .capabilities()
+        .contains(Capabilities::EXTENSION_PROTOCOL));
+}
+
+#[test]
+fn turn_away_peers_asking_for_unknown_torrents() {
+    let listener = PeerListener::bind("127.0.0.1:0").unwrap();
+    let torrent_file = sample_torrent_file();
+    let info_hash = torrent_file.info.hash_info().unwrap();
+    let inbound_peers = listener.register(&torrent_file).unwrap();
+
+    let mut stream = TcpStream::connect(listener.local_addr()).unwrap();
+    let error = Handshake::new([7; 20], PeerId::generate())
+        .exchange(&mut stream)
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<HandshakeError>(),
+        Some(&HandshakeError::Truncated(0))
+    );
+
+    // Unregistered torrents are unknown as well
+    listener.unregister(&info_hash);
+    let mut stream = TcpStream::connect(listener.local_addr()).unwrap();
+    assert!(Handshake::new(info_hash, PeerId::generate())
+        .exchange(&mut stream)
+        .is_err());
+    assert!(inbound_peers.recv_timeout(Duration::from_secs(1)).is_err());
+}
+
+#[test]
+fn accept_ipv4_and_ipv6_peers_on_any_address() {
+    let listener = PeerListener::bind_any(0).unwrap();
+    let torrent_file = sample_torrent_file();
+    let info_hash = torrent_file.info.hash_info().unwrap();
+    let inbound_peers = listener.register(&torrent_file).unwrap();
+
+    for ip in [
+        IpAddr::from(Ipv4Addr::LOCALHOST),
+        Ipv6Addr::LOCALHOST.into(),
+    ] {
+        let mut stream = TcpStream::connect((ip, listener.port())).unwrap();
+        Handshake::new(info_hash, PeerId::generate())
+            .exchange(&mut stream)
+            .unwrap();
+        // IPv4 peers keep their own address, not an IPv4-mapped IPv6 one
+        let (peer_addr, _) = inbound_peers.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(peer_addr, stream.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::Cursor;
+
+use bittorrent_starter_rust::peer_message::{MessageError, PeerMessage, MAX_MESSAGE_LENGTH};
+
+#[test]
+fn round_trip_every_message() {
+    let messages = [
+        PeerMessage::KeepAlive,
+        PeerMessage::Choke,
+        PeerMessage::Unchoke,
+        PeerMessage::Interested,
+        PeerMessage::NotInterested,
+        PeerMessage::Have(7),
+        PeerMessage::Bitfield(vec![0b1010_0000, 0b1000_0000]),
+        PeerMessage::Request {
+            index: 1,
+            begin: 1 << 14,
+            length: 1 << 14,
+        },
+        PeerMessage::Piece {
+            index: 1,
+            begin: 1 << 14,
+            block: vec![1, 2, 3],
+        },
+        PeerMessage::Cancel {
+            index: 1,
+            begin: 0,
+            length: 1 << 14,
+        },
+        PeerMessage::Port(6881),
+        PeerMessage::Extended {
+            id: 0,
+            payload: b"d1:md11:ut_metadatai1eee".to_vec(),
+        },
+        PeerMessage::Unknown {
+            id: 13,
+            payload: vec![0, 0, 0, 3],
+        },
+    ];
+
+    let mut stream = vec![];
+    for message in &messages {
+        assert_eq!(PeerMessage::parse(&message.to_bytes()).unwrap(), *message);
+        message.write_to(&mut stream).unwrap();
+    }
+    let mut stream = Cursor::new(stream);
+    for message in &messages {
+        assert_eq!(PeerMessage::read_from(&mut stream).unwrap(), *message);
+    }
+}
+
+#[test]
+fn encode_messages_as_on_the_wire() {
+    assert_eq!(PeerMessage::KeepAlive.to_bytes(), [0, 0, 0, 0]);
+    assert_eq!(PeerMessage::Unchoke.to_bytes(), [0, 0, 0, 1, 1]);
+    assert_eq!(
+        PeerMessage::Have(258).to_bytes(),
+        [0, 0, 0, 5, 4, 0, 0, 1, 2]
+    );
+    assert_eq!(
+        PeerMessage::Request {
+            index: 1,
+            begin: 2,
+            length: 3
+        }
+        .to_bytes(),
+        [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+    );
+}
+
+#[test]
+fn reject_malformed_messages() {
+    assert_eq!(
+        PeerMessage::parse(&[0, 0, 0, 5, 4, 0]),
+        Err(MessageError::Truncated {
+            expected: 9,
+            got: 6
+        })
+    );
+    assert_eq!(
+        PeerMessage::parse(&[0, 0, 0, 3, 4, 0, 1]),
+        Err(MessageError::InvalidLength { id: 4, length: 3 })
+    );
+    assert_eq!(
+        PeerMessage::parse(&[0, 0, 0, 2, 1, 0]),
+        Err(MessageError::InvalidLength { id: 1, length: 2 })
+    );
+}
+
+#[test]
+fn refuse_messages_longer_than_the_maximum() {
+    let length = MAX_MESSAGE_LENGTH + 1;
+    assert_eq!(
+        PeerMessage::parse(&length.to_be_bytes()),
+        Err(MessageError::TooLong(length))
+    );
+
+    // Only the length prefix is there, so nothing can have been read past it
+    let error = PeerMessage::read_from(&mut Cursor::new(length.to_be_bytes())).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<MessageError>(),
+        Some(&MessageError::TooLong(length))
+    );
+}
@@ -0,0 +1,66 @@
+use std::fs;
+use std::path::PathBuf;
+
+use bittorrent_starter_rust::source::{list_torrent_files, TorrentSource};
+
+#[test]
+fn parse_torrent_sources() {
+    assert_eq!("-".parse::<TorrentSource>().unwrap(), TorrentSource::Stdin);
+    assert_eq!(
+        "https://example.com/sample.torrent"
+            .parse::<TorrentSource>()
+            .unwrap(),
+        TorrentSource::Url("https://example.com/sample.torrent".to_string())
+    );
+    assert!(matches!(
+        "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+            .parse::<TorrentSource>()
+            .unwrap(),
+        TorrentSource::Magnet(_)
+    ));
+    assert_eq!(
+        "sample.torrent".parse::<TorrentSource>().unwrap(),
+        TorrentSource::File(PathBuf::from("sample.torrent"))
+    );
+    assert_eq!(
+        "src".parse::<TorrentSource>().unwrap(),
+        TorrentSource::Directory(PathBuf::from("src"))
+    );
+}
+
+#[test]
+fn load_a_directory_of_torrents() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::copy("sample.torrent", dir.path().join("b.torrent")).unwrap();
+    fs::copy("sample.torrent", dir.path().join("a.torrent")).unwrap();
+    fs::write(dir.path().join("notes.txt"), "not a torrent").unwrap();
+
+    assert_eq!(
+        list_torrent_files(dir.path()).unwrap(),
+        vec![dir.path().join("a.torrent"), dir.path().join("b.torrent")]
+    );
+
+    let source = dir
+        .path()
+        .to_str()
+        .unwrap()
+        .parse::<TorrentSource>()
+        .unwrap();
+    assert!(source.is_batch());
+    let torrent_files = source.load().unwrap();
+    assert_eq!(torrent_files.len(), 2);
+    assert_eq!(torrent_files[0].info.name, "sample.txt");
+    assert!(source.load_one().is_err());
+}
+
+#[test]
+fn load_a_single_torrent_file() {
+    let torrent_file = TorrentSource::File(PathBuf::from("sample.torrent"))
+        .load_one()
+        .unwrap();
+    assert_eq!(
+        torrent_file.announce,
+        "http://bittorrent-test-tracker.codecrafters.io/announce"
+    );
+    assert_eq!(torrent_file.info.length, 92063);
+}
@@ -1,15 +1,24 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use bittorrent_starter_rust::{
+    peer_id::PeerId,
     torrent_file::{TorrentFile, TorrentFileInfo},
-    tracker::{get_request_url, parse_response, PeerAddr, TrackerResponse},
+    tracker::{
+        announce, announce_key, get_request_url, parse_response, parse_scrape_response, scrape,
+        scrape_url, track, AnnounceEvent, AnnounceParams, PeerAddr, PeerHost, ScrapeStats,
+        TrackerError, TrackerResponse,
+    },
 };
 
+mod common;
+use common::spawn_http_tracker;
+
 #[test]
 fn create_url_from_torrent_file() {
     assert_eq!(
         get_request_url(&TorrentFile {
             announce: "http://bittorrent-test-tracker.codecrafters.io/announce".to_string(),
+            announce_list: vec![],
             info: TorrentFileInfo {
                 name: "sample.txt".to_string(),
                 piece_length: 32768,
@@ -20,10 +29,16 @@ fn create_url_from_torrent_file() {
                     114, 39, 173, 158, 144, 154, 204, 23,
                 ],
                 length: 92063,
+                files: None,
+                info_hash: None,
             },
         })
         .unwrap(),
-        "http://bittorrent-test-tracker.codecrafters.io/announce?info_hash=%d6%9f%91%e6%b2%ae%4c%54%24%68%d1%07%3a%71%d4%ea%13%87%9a%7f&peer_id=00112233445566778899&port=6881&uploaded=0&downloaded=0&left=92063&compact=1"
+        format!(
+            "http://bittorrent-test-tracker.codecrafters.io/announce?info_hash=%d6%9f%91%e6%b2%ae%4c%54%24%68%d1%07%3a%71%d4%ea%13%87%9a%7f&peer_id={}&port=6881&uploaded=0&downloaded=0&left=92063&compact=1&key={:08x}",
+            PeerId::session(),
+            announce_key()
+        )
     )
 }
 
@@ -39,24 +54,274 @@ fn create_track_response() {
         ])
         .unwrap(),
         TrackerResponse {
-            complete: 3,
-            min_interval: 60,
-            incomplete: 1,
+            complete: Some(3),
+            min_interval: Some(60),
+            incomplete: Some(1),
             interval: 60,
             peer_addr_list: vec![
-                PeerAddr {
-                    ip: Ipv4Addr::new(178, 62, 82, 89),
-                    port: 51470
-                },
-                PeerAddr {
-                    ip: Ipv4Addr::new(165, 232, 33, 77),
-                    port: 51467
-                },
-                PeerAddr {
-                    ip: Ipv4Addr::new(178, 62, 85, 20),
-                    port: 51489
-                }
-            ]
+                PeerAddr::new(SocketAddr::from((Ipv4Addr::new(178, 62, 82, 89), 51470))),
+                PeerAddr::new(SocketAddr::from((Ipv4Addr::new(165, 232, 33, 77), 51467))),
+                PeerAddr::new(SocketAddr::from((Ipv4Addr::new(178, 62, 85, 20), 51489)))
+            ],
+            peer_hosts: vec![],
+            warning_message: None,
+            tracker_id: None,
+        }
+    )
+}
+
+#[test]
+fn parse_non_compact_and_ipv6_peers() {
+    let mut response =
+        b"d8:intervali60e12:min intervali60e8:completei1e10:incompletei0e5:peersl".to_vec();
+    response.extend(b"d2:ip8:10.0.0.17:peer id20:-qB4630-abcdefghijkl4:porti6881ee");
+    response.extend(b"d2:ip3:::14:porti6882ee");
+    response.extend(b"d2:ip9:localhost4:porti6883ee");
+    // Unusable entries are skipped
+    response.extend(b"d2:ip8:10.0.0.14:porti99999ee");
+    response.extend(b"e6:peers618:");
+    response.extend(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+    response.extend(6884u16.to_be_bytes());
+    response.extend(b"e");
+
+    let response = parse_response(&response).unwrap();
+    let peer_addr_list = response.peer_addr_list;
+    assert_eq!(peer_addr_list.len(), 3);
+    assert_eq!(
+        peer_addr_list[0],
+        PeerAddr {
+            addr: SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 6881)),
+            peer_id: Some(*b"-qB4630-abcdefghijkl"),
+        }
+    );
+    assert_eq!(
+        peer_addr_list[1],
+        PeerAddr::new(SocketAddr::from((Ipv6Addr::LOCALHOST, 6882)))
+    );
+    assert_eq!(
+        peer_addr_list[2],
+        PeerAddr::new(SocketAddr::from((
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+            6884
+        )))
+    );
+    assert_eq!(peer_addr_list[2].to_string(), "[2001:db8::1]:6884");
+    // Hostnames are only looked up when announcing
+    assert_eq!(
+        response.peer_hosts,
+        vec![PeerHost {
+            host: "localhost".to_string(),
+            port: 6883,
+            peer_id: None,
+        }]
+    );
+}
+
+#[test]
+fn look_up_peers_named_by_hostname_when_announcing() {
+    let (announce_url, _) =
+        spawn_http_tracker(b"d8:intervali900e5:peersld2:ip9:localhost4:porti6883eeee");
+
+    let response = announce(&announce_url, &AnnounceParams::new([4; 20], 10)).unwrap();
+    assert_eq!(response.peer_addr_list.len(), 1);
+    assert!(response.peer_addr_list[0].addr.ip().is_loopback());
+    assert_eq!(response.peer_addr_list[0].addr.port(), 6883);
+    assert_eq!(response.peer_hosts, vec![]);
+}
+
+#[test]
+fn parse_a_tracker_failure() {
+    let error = parse_response(b"d14:failure reason20:unregistered torrente").unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<TrackerError>(),
+        Some(TrackerError::Failure(reason)) if reason == "unregistered torrent"
+    ));
+}
+
+#[test]
+fn reject_a_truncated_response() {
+    assert!(parse_response(b"").is_err());
+    assert!(parse_response(b"d8:intervali900e5:peers99:x").is_err());
+}
+
+#[test]
+fn parse_a_minimal_response_with_warning_and_tracker_id() {
+    assert_eq!(
+        parse_response(b"d8:intervali900e15:warning message10:slow down!10:tracker id3:abce")
+            .unwrap(),
+        TrackerResponse {
+            complete: None,
+            min_interval: None,
+            incomplete: None,
+            interval: 900,
+            peer_addr_list: vec![],
+            peer_hosts: vec![],
+            warning_message: Some("slow down!".to_string()),
+            tracker_id: Some(b"abc".to_vec()),
         }
+    );
+}
+
+#[test]
+fn send_the_tracker_id_back_on_later_announces() {
+    let (announce_url, heads) = spawn_http_tracker(b"d8:intervali900e10:tracker id4:t-42e");
+
+    announce(&announce_url, &AnnounceParams::new([1; 20], 10)).unwrap();
+    announce(&announce_url, &AnnounceParams::new([1; 20], 10)).unwrap();
+
+    assert!(!heads.recv().unwrap()[0].contains("trackerid"));
+    assert!(heads.recv().unwrap()[0].contains("&trackerid=%74%2d%34%32"));
+}
+
+#[test]
+fn keep_the_passkey_of_private_trackers() {
+    let (announce_url, heads) = spawn_http_tracker(b"d8:intervali900ee");
+
+    announce(
+        &format!("{}?passkey=abc", announce_url),
+        &AnnounceParams::new([3; 20], 10),
     )
+    .unwrap();
+
+    let request_line = &heads.recv().unwrap()[0];
+    assert!(request_line.contains("/announce?passkey=abc&info_hash=%03%03"));
+}
+
+#[test]
+fn report_the_event_and_transfer_stats() {
+    let (announce_url, heads) = spawn_http_tracker(b"d8:intervali900ee");
+
+    let mut params = AnnounceParams::new([2; 20], 1000);
+    params.record_downloaded(400);
+    params.record_uploaded(50);
+    params.event = Some(AnnounceEvent::Completed);
+    params.numwant = Some(30);
+    params.key = Some(0xbeef);
+    params.ip = Some(Ipv6Addr::LOCALHOST.into());
+    params.no_peer_id = true;
+    announce(&announce_url, &params).unwrap();
+
+    let request_line = &heads.recv().unwrap()[0];
+    assert!(request_line.contains(
+        "&uploaded=50&downloaded=400&left=600&compact=1&no_peer_id=1&event=completed&numwant=30&key=0000beef&ip=%3a%3a1 "
+    ));
+}
+
+#[test]
+fn derive_scrape_urls() {
+    for (announce_url, expected) in [
+        (
+            "http://example.com/announce",
+            Some("http://example.com/scrape"),
+        ),
+        (
+            "http://example.com/x/announce",
+            Some("http://example.com/x/scrape"),
+        ),
+        (
+            "http://example.com/announce.php",
+            Some("http://example.com/scrape.php"),
+        ),
+        (
+            "http://example.com/announce?x2%0644",
+            Some("http://example.com/scrape?x2%0644"),
+        ),
+        ("http://example.com/a", None),
+        (
+            "http://example.com/announce?x=2/4",
+            Some("http://example.com/scrape?x=2/4"),
+        ),
+        ("http://example.com/x%064announce", None),
+    ] {
+        assert_eq!(
+            scrape_url(announce_url).as_deref(),
+            expected,
+            "{}",
+            announce_url
+        );
+    }
+}
+
+#[test]
+fn parse_a_scrape_response() {
+    let mut response = b"d5:filesd20:".to_vec();
+    response.extend([1; 20]);
+    response.extend(b"d8:completei5e10:downloadedi50e10:incompletei10ee20:");
+    response.extend([2; 20]);
+    response.extend(b"d8:completei1eeee");
+
+    let stats = parse_scrape_response(&response).unwrap();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(
+        stats[&[1; 20]],
+        ScrapeStats {
+            complete: 5,
+            downloaded: 50,
+            incomplete: 10
+        }
+    );
+    assert_eq!(
+        stats[&[2; 20]],
+        ScrapeStats {
+            complete: 1,
+            downloaded: 0,
+            incomplete: 0
+        }
+    );
+
+    assert!(matches!(
+        parse_scrape_response(b"d14:failure reason9:forbiddene")
+            .unwrap_err()
+            .downcast_ref::<TrackerError>(),
+        Some(TrackerError::Failure(reason)) if reason == "forbidden"
+    ));
+}
+
+#[test]
+fn scrape_several_torrents_at_once() {
+    let mut body = b"d5:filesd20:".to_vec();
+    body.extend([1; 20]);
+    body.extend(b"d8:completei3eeee");
+    let (announce_url, heads) = spawn_http_tracker(&body);
+
+    let stats = scrape(&announce_url, &[[1; 20], [2; 20]]).unwrap();
+    assert_eq!(stats[&[1; 20]].complete, 3);
+    assert!(!stats.contains_key(&[2; 20]));
+
+    let request_line = &heads.recv().unwrap()[0];
+    assert!(request_line.starts_with(
+        "GET /scrape?info_hash=%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01&info_hash=%02"
+    ));
+}
+
+#[test]
+fn track_every_tracker_of_the_torrent() {
+    let (first_url, first_heads) =
+        spawn_http_tracker(b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe1e");
+    let (second_url, second_heads) =
+        spawn_http_tracker(b"d8:intervali900e5:peers6:\x7f\x00\x00\x02\x1a\xe1e");
+    let torrent_file = TorrentFile {
+        announce: first_url.clone(),
+        announce_list: vec![vec![first_url.clone()], vec![second_url.clone()]],
+        info: TorrentFileInfo {
+            name: "a".to_string(),
+            piece_length: 1,
+            pieces: vec![0; 20],
+            length: 1,
+            files: None,
+            info_hash: None,
+        },
+    };
+
+    let track_result = track(&torrent_file).unwrap();
+    assert_eq!(
+        track_result.peer_addr_list,
+        vec![
+            PeerAddr::new(SocketAddr::from((Ipv4Addr::new(127, 0, 0, 1), 6881))),
+            PeerAddr::new(SocketAddr::from((Ipv4Addr::new(127, 0, 0, 2), 6881))),
+        ]
+    );
+    assert_eq!(track_result.responses.len(), 2);
+    assert!(first_heads.recv().is_ok());
+    assert!(second_heads.recv().is_ok());
 }
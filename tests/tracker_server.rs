@@ -0,0 +1,172 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::tracker::{
+    announce, parse_response, scrape, AnnounceEvent, AnnounceParams, PeerAddr, TrackerError,
+};
+use bittorrent_starter_rust::tracker_server::{TrackerConfig, TrackerServer};
+
+mod common;
+use common::spawn_tracker_server;
+
+const INFO_HASH: [u8; 20] = [5; 20];
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+fn params(peer_id: &[u8; 20], port: u16, left: u64) -> AnnounceParams {
+    let mut params = AnnounceParams::new(INFO_HASH, left);
+    params.peer_id = PeerId::from(*peer_id);
+    params.port = port;
+    params
+}
+
+#[test]
+fn hand_out_the_other_peers_of_a_swarm() {
+    let announce_url = spawn_tracker_server(TrackerConfig::default());
+
+    let seeder = params(b"-AA0001-aaaaaaaaaaaa", 7001, 0);
+    let response = announce(&announce_url, &seeder).unwrap();
+    assert!(response.peer_addr_list.is_empty());
+    assert_eq!(response.interval, 1800);
+
+    let leecher = params(b"-BB0001-bbbbbbbbbbbb", 7002, 100);
+    let response = announce(&announce_url, &leecher).unwrap();
+    assert_eq!(
+        response.peer_addr_list,
+        vec![PeerAddr::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 7001)))]
+    );
+    assert_eq!(response.complete, Some(1));
+    assert_eq!(response.incomplete, Some(1));
+
+    let mut completed = leecher.clone();
+    completed.left = 0;
+    completed.event = Some(AnnounceEvent::Completed);
+    announce(&announce_url, &completed).unwrap();
+    let stats = scrape(&announce_url, &[INFO_HASH, [6; 20]]).unwrap();
+    assert_eq!(stats[&INFO_HASH].complete, 2);
+    assert_eq!(stats[&INFO_HASH].incomplete, 0);
+    assert_eq!(stats[&INFO_HASH].downloaded, 1);
+    assert!(!stats.contains_key(&[6; 20]));
+
+    let mut stopped = seeder.clone();
+    stopped.event = Some(AnnounceEvent::Stopped);
+    announce(&announce_url, &stopped).unwrap();
+    assert!(announce(&announce_url, &leecher)
+        .unwrap()
+        .peer_addr_list
+        .is_empty());
+}
+
+#[test]
+fn answer_with_non_compact_lists_and_ipv6_peers() {
+    let tracker = TrackerServer::new(TrackerConfig::default());
+    let info_hash = "%05".repeat(20);
+    tracker.announce(
+        &format!(
+            "info_hash={}&peer_id=-AA0001-aaaaaaaaaaaa&port=7001&left=0&ip=%3a%3a1",
+            info_hash
+        ),
+        LOCALHOST,
+    );
+
+    let response = parse_response(&tracker.announce(
+        &format!(
+            "info_hash={}&peer_id=-BB0001-bbbbbbbbbbbb&port=7002&left=5",
+            info_hash
+        ),
+        LOCALHOST,
+    ))
+    .unwrap();
+    assert_eq!(
+        response.peer_addr_list,
+        vec![PeerAddr::new(SocketAddr::from((Ipv6Addr::LOCALHOST, 7001)))]
+    );
+
+    let response = parse_response(&tracker.announce(
+        &format!(
+            "info_hash={}&peer_id=-BB0001-bbbbbbbbbbbb&port=7002&left=5&compact=0",
+            info_hash
+        ),
+        LOCALHOST,
+    ))
+    .unwrap();
+    assert_eq!(
+        response.peer_addr_list,
+        vec![PeerAddr {
+            addr: SocketAddr::from((Ipv6Addr::LOCALHOST, 7001)),
+            peer_id: Some(*b"-AA0001-aaaaaaaaaaaa"),
+        }]
+    );
+
+    let response = parse_response(&tracker.announce(
+        &format!(
+            "info_hash={}&peer_id=-BB0001-bbbbbbbbbbbb&port=7002&left=5&compact=0&no_peer_id=1",
+            info_hash
+        ),
+        LOCALHOST,
+    ))
+    .unwrap();
+    assert_eq!(response.peer_addr_list[0].peer_id, None);
+}
+
+#[test]
+fn expire_peers_that_stopped_announcing() {
+    let announce_url = spawn_tracker_server(TrackerConfig {
+        peer_timeout: Duration::from_millis(100),
+        ..Default::default()
+    });
+
+    announce(&announce_url, &params(b"-AA0001-aaaaaaaaaaaa", 7001, 0)).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let response = announce(&announce_url, &params(b"-BB0001-bbbbbbbbbbbb", 7002, 100)).unwrap();
+    assert!(response.peer_addr_list.is_empty());
+}
+
+#[test]
+fn only_track_whitelisted_info_hashes() {
+    let announce_url = spawn_tracker_server(TrackerConfig {
+        whitelist: Some([[6; 20]].into()),
+        ..Default::default()
+    });
+
+    let error = announce(&announce_url, &params(b"-AA0001-aaaaaaaaaaaa", 7001, 0)).unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<TrackerError>(),
+        Some(TrackerError::Failure(_))
+    ));
+}
+
+#[test]
+fn reject_invalid_announces() {
+    let tracker = TrackerServer::new(TrackerConfig::default());
+    assert!(matches!(
+        parse_response(&tracker.announce("info_hash=short&port=1", LOCALHOST))
+            .unwrap_err()
+            .downcast_ref::<TrackerError>(),
+        Some(TrackerError::Failure(_))
+    ));
+}
+
+#[test]
+fn hang_up_on_requests_that_are_too_large() {
+    let announce_url = spawn_tracker_server(TrackerConfig::default());
+    let tracker_addr = announce_url
+        .trim_start_matches("http://")
+        .trim_end_matches("/announce");
+    let mut stream = TcpStream::connect(tracker_addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    // A request line that never ends
+    let _ = stream.write_all(&[b'a'; 1 << 16]);
+    let mut response = vec![];
+    if let Err(e) = stream.read_to_end(&mut response) {
+        assert!(!matches!(
+            e.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+    }
+}
@@ -0,0 +1,227 @@
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use bittorrent_starter_rust::tracker::{
+    announce, announce_key, scrape, AnnounceParams, PeerAddr, ScrapeStats, TrackerError,
+};
+use bittorrent_starter_rust::udp_tracker::UdpTracker;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const CONNECTION_ID: u64 = 0xC0FFEE;
+const INFO_HASH: [u8; 20] = [7; 20];
+
+#[derive(Default)]
+struct Behaviour {
+    /// Swallow this many requests without answering, to force retransmissions
+    drop_first: usize,
+    /// Answer with a wrong transaction ID before every real answer
+    send_stale_answers: bool,
+    /// Answer announces with an error
+    fail_announces: bool,
+}
+
+struct StandInTracker {
+    url: String,
+    connects: Arc<AtomicUsize>,
+    requests: Arc<AtomicUsize>,
+}
+
+fn spawn_tracker(behaviour: Behaviour) -> StandInTracker {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+    let connects = Arc::new(AtomicUsize::new(0));
+    let requests = Arc::new(AtomicUsize::new(0));
+
+    let (thread_connects, thread_requests) = (connects.clone(), requests.clone());
+    thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+        while let Ok((length, from)) = socket.recv_from(&mut buf) {
+            let request = &buf[..length];
+            if thread_requests.fetch_add(1, Ordering::SeqCst) < behaviour.drop_first {
+                continue;
+            }
+            let connection_id = u64::from_be_bytes(request[0..8].try_into().unwrap());
+            let action = u32::from_be_bytes(request[8..12].try_into().unwrap());
+            let transaction_id = u32::from_be_bytes(request[12..16].try_into().unwrap());
+
+            let mut response: Vec<u8> = vec![];
+            match action {
+                0 => {
+                    assert_eq!(connection_id, PROTOCOL_ID);
+                    thread_connects.fetch_add(1, Ordering::SeqCst);
+                    response.extend(0u32.to_be_bytes());
+                    response.extend(transaction_id.to_be_bytes());
+                    response.extend(CONNECTION_ID.to_be_bytes());
+                }
+                1 if behaviour.fail_announces => {
+                    response.extend(3u32.to_be_bytes());
+                    response.extend(transaction_id.to_be_bytes());
+                    response.extend(b"unregistered torrent");
+                }
+                1 => {
+                    assert_eq!(connection_id, CONNECTION_ID);
+                    assert_eq!(length, 98);
+                    assert_eq!(&request[16..36], &INFO_HASH);
+                    // The same key as over HTTP
+                    assert_eq!(request[88..92], announce_key().to_be_bytes());
+                    response.extend(1u32.to_be_bytes());
+                    response.extend(transaction_id.to_be_bytes());
+                    response.extend(1800u32.to_be_bytes()); // interval
+                    response.extend(2u32.to_be_bytes()); // leechers
+                    response.extend(5u32.to_be_bytes()); // seeders
+                    response.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+                    response.extend([10, 0, 0, 2, 0x1a, 0xe2]);
+                }
+                2 => {
+                    assert_eq!(connection_id, CONNECTION_ID);
+                    response.extend(2u32.to_be_bytes());
+                    response.extend(transaction_id.to_be_bytes());
+                    for (i, _) in request[16..].chunks(20).enumerate() {
+                        response.extend((i as u32 + 1).to_be_bytes()); // seeders
+                        response.extend(10u32.to_be_bytes()); // completed
+                        response.extend(3u32.to_be_bytes()); // leechers
+                    }
+                }
+                _ => unreachable!(),
+            }
+            if behaviour.send_stale_answers {
+                let mut stale = response.clone();
+                stale[4..8].copy_from_slice(&transaction_id.wrapping_add(1).to_be_bytes());
+                socket.send_to(&stale, from).unwrap();
+            }
+            socket.send_to(&response, from).unwrap();
+        }
+    });
+
+    StandInTracker {
+        url,
+        connects,
+        requests,
+    }
+}
+
+#[test]
+fn announce_to_a_udp_tracker() {
+    let tracker = spawn_tracker(Behaviour::default());
+
+    let response = announce(&tracker.url, &AnnounceParams::new(INFO_HASH, 100)).unwrap();
+    assert_eq!(response.complete, Some(5));
+    assert_eq!(response.incomplete, Some(2));
+    assert_eq!(response.interval, 1800);
+    assert_eq!(
+        response.peer_addr_list,
+        vec![
+            PeerAddr::new(SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 6881))),
+            PeerAddr::new(SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), 6882))),
+        ]
+    );
+
+    // The connection ID is cached, so the second announce skips the connect
+    announce(&tracker.url, &AnnounceParams::new(INFO_HASH, 100)).unwrap();
+    assert_eq!(tracker.connects.load(Ordering::SeqCst), 1);
+    assert_eq!(tracker.requests.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn retransmit_unanswered_requests() {
+    let tracker = spawn_tracker(Behaviour {
+        drop_first: 2,
+        ..Default::default()
+    });
+
+    let response = UdpTracker::new(&tracker.url)
+        .unwrap()
+        .with_timeouts(Duration::from_millis(50), 3)
+        .announce(&AnnounceParams::new(INFO_HASH, 100))
+        .unwrap();
+    assert_eq!(response.peer_addr_list.len(), 2);
+    assert_eq!(tracker.requests.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn give_up_after_the_last_retransmission() {
+    let tracker = spawn_tracker(Behaviour {
+        drop_first: usize::MAX,
+        ..Default::default()
+    });
+
+    assert!(UdpTracker::new(&tracker.url)
+        .unwrap()
+        .with_timeouts(Duration::from_millis(10), 2)
+        .announce(&AnnounceParams::new(INFO_HASH, 100))
+        .is_err());
+    assert_eq!(tracker.requests.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn ignore_answers_with_another_transaction_id() {
+    let tracker = spawn_tracker(Behaviour {
+        send_stale_answers: true,
+        ..Default::default()
+    });
+
+    let response = UdpTracker::new(&tracker.url)
+        .unwrap()
+        .announce(&AnnounceParams::new(INFO_HASH, 100))
+        .unwrap();
+    assert_eq!(response.complete, Some(5));
+}
+
+#[test]
+fn surface_udp_tracker_errors() {
+    let tracker = spawn_tracker(Behaviour {
+        fail_announces: true,
+        ..Default::default()
+    });
+
+    let error = UdpTracker::new(&tracker.url)
+        .unwrap()
+        .announce(&AnnounceParams::new(INFO_HASH, 100))
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<TrackerError>(),
+        Some(TrackerError::Failure(reason)) if reason == "unregistered torrent"
+    ));
+}
+
+#[test]
+fn scrape_a_udp_tracker() {
+    let tracker = spawn_tracker(Behaviour::default());
+
+    assert_eq!(
+        UdpTracker::new(&tracker.url)
+            .unwrap()
+            .scrape(&[[1; 20], [2; 20]])
+            .unwrap(),
+        vec![
+            ScrapeStats {
+                complete: 1,
+                downloaded: 10,
+                incomplete: 3
+            },
+            ScrapeStats {
+                complete: 2,
+                downloaded: 10,
+                incomplete: 3
+            },
+        ]
+    );
+}
+
+#[test]
+fn scrape_more_torrents_than_fit_in_one_udp_request() {
+    let tracker = spawn_tracker(Behaviour::default());
+
+    let info_hashes: Vec<[u8; 20]> = (0..100u8).map(|i| [i; 20]).collect();
+    let stats = scrape(&tracker.url, &info_hashes).unwrap();
+    assert_eq!(stats.len(), 100);
+    assert_eq!(stats[&[0; 20]].complete, 1);
+    assert_eq!(stats[&[74; 20]].complete, 1);
+    assert_eq!(stats[&[99; 20]].complete, 26);
+}